@@ -4,6 +4,49 @@
 #include <stdlib.h>
 #include <sys/stat.h>
 
+/**
+ * Bumped when the archive format changes. Only archives of this version can be restored.
+ */
+#define ARCHIVE_VERSION 1
+
+/**
+ * The number of changes kept if Limits::max_change_log_len isn't set.
+ */
+#define DEFAULT_MAX_CHANGE_LOG_LEN (1 << 16)
+
+/**
+ * The number of files Handle::import_dir commits at a time by default.
+ */
+#define DEFAULT_IMPORT_BATCH_SIZE 256
+
+/**
+ * The kind of mutation recorded in the change log.
+ */
+typedef enum {
+  /**
+   * A new value was committed for the key.
+   */
+  Write = 1,
+  /**
+   * The value was given a new key. The key it had before is in Change::from_key.
+   */
+  Rename = 2,
+  /**
+   * The key was deleted.
+   */
+  Delete = 3,
+  /**
+   * The key was evicted to satisfy limits.
+   */
+  Evict = 4,
+} ChangeOp;
+
+typedef enum {
+  Unbounded,
+  Included,
+  Excluded,
+} PossumBoundKind;
+
 typedef enum {
   NoError,
   NoSuchKey,
@@ -11,6 +54,12 @@ typedef enum {
   IoError,
   AnyhowError,
   UnsupportedFilesystem,
+  WriteConflict,
+  ValueLengthMismatch,
+  IncompleteValue,
+  InvalidArchive,
+  NoSuchSnapshot,
+  ChangesDropped,
 } PossumError;
 
 /**
@@ -61,15 +110,43 @@ typedef struct {
   PossumStat stat;
 } PossumItem;
 
+/**
+ * One end of a key range. The key is ignored if the bound is Unbounded.
+ */
+typedef struct {
+  PossumBoundKind kind;
+  PossumBuf key;
+} PossumKeyBound;
+
 typedef struct {
   uint64_t max_value_length_sum;
   bool disable_hole_punching;
 } PossumLimits;
 
+/**
+ * Sequence number of a committed mutation. These increase monotonically and are never reused.
+ */
+typedef uint64_t ChangeSeq;
+
+typedef struct {
+  ChangeSeq seq;
+  ChangeOp op;
+  PossumBuf key;
+  /**
+   * Null if the change isn't a rename.
+   */
+  PossumBuf from_key;
+  PossumStat stat;
+} PossumChange;
+
 Handle *possum_new(const char *path);
 
 PossumError possum_start_new_value(PossumWriter *writer, PossumValueWriter **value);
 
+PossumError possum_start_new_value_with_size(PossumWriter *writer,
+                                             uint64_t length,
+                                             PossumValueWriter **value);
+
 RawFileHandle possum_value_writer_fd(PossumValueWriter *value);
 
 PossumError possum_writer_rename(BatchWriter *writer, const PossumValue *value, PossumBuf new_key);
@@ -95,10 +172,69 @@ PossumError possum_reader_list_items(const PossumReader *reader,
                                      PossumItem **out_items,
                                      size_t *out_len);
 
+/**
+ * Lists up to limit items with keys between start and end as of the reader's transaction, or all
+ * of them if limit is 0, in descending order if reverse is set.
+ */
+PossumError possum_reader_list_range(const PossumReader *reader,
+                                     PossumKeyBound start,
+                                     PossumKeyBound end,
+                                     bool reverse,
+                                     size_t limit,
+                                     PossumItem **out_items,
+                                     size_t *out_len);
+
+PossumError possum_reader_count_range(const PossumReader *reader,
+                                      PossumKeyBound start,
+                                      PossumKeyBound end,
+                                      uint64_t *out_count);
+
 PossumError possum_writer_commit(PossumWriter *writer);
 
 PossumError possum_writer_stage(PossumWriter *writer, PossumBuf key, PossumValueWriter *value);
 
+/**
+ * Stages a value that may not have been entirely written with possum_value_writer_write_at.
+ */
+PossumError possum_writer_stage_incomplete(PossumWriter *writer,
+                                           PossumBuf key,
+                                           PossumValueWriter *value);
+
+/**
+ * Writes buf at offset in a value started with possum_start_new_value_with_size.
+ */
+PossumError possum_value_writer_write_at(PossumValueWriter *value, uint64_t offset, PossumBuf buf);
+
+/**
+ * Appends bufs to the value, setting written to the number of bytes written. Like writev, this may
+ * write less than all of bufs.
+ */
+PossumError possum_value_writer_write_vectored(PossumValueWriter *value,
+                                               const PossumBuf *bufs,
+                                               size_t bufs_len,
+                                               size_t *written);
+
+/**
+ * Appends length bytes from file at offset to the value, without passing through user space where
+ * supported. copied is set to the number of bytes copied, which is less than length if the file
+ * ends first.
+ */
+PossumError possum_value_writer_copy_from_file(PossumValueWriter *value,
+                                               RawFileHandle file,
+                                               uint64_t offset,
+                                               uint64_t length,
+                                               uint64_t *copied);
+
+/**
+ * Sends the value from offset to fd, which can be a file, pipe or socket, with splice or sendfile
+ * where supported. sent is set to the number of bytes sent, which can be fewer than the rest of
+ * the value if fd is nonblocking. Only supported on Unix.
+ */
+PossumError possum_value_send_to(const PossumValue *value,
+                                 uint64_t offset,
+                                 RawFileHandle fd,
+                                 uint64_t *sent);
+
 void possum_drop(Handle *handle);
 
 PossumError possum_set_instance_limits(Handle *handle, const PossumLimits *limits);
@@ -116,6 +252,46 @@ PossumError possum_list_items(const Handle *handle,
                               PossumItem **out_list,
                               size_t *out_list_len);
 
+/**
+ * Lists up to limit items with prefix in key order. A limit of 0 is rejected with IoError, as it
+ * would never make progress; pass SIZE_MAX for no limit. Like the returned keys, start_after is
+ * relative to prefix, and listing continues after it unless its ptr is null. Pass the last key of
+ * the previous page to get the next page.
+ */
+PossumError possum_list_items_page(const Handle *handle,
+                                   PossumBuf prefix,
+                                   PossumBuf start_after,
+                                   size_t limit,
+                                   bool reverse,
+                                   PossumItem **out_list,
+                                   size_t *out_list_len);
+
+/**
+ * Lists up to limit items with keys between start and end, or all of them if limit is 0, in
+ * descending order if reverse is set.
+ */
+PossumError possum_list_range(const Handle *handle,
+                              PossumKeyBound start,
+                              PossumKeyBound end,
+                              bool reverse,
+                              size_t limit,
+                              PossumItem **out_list,
+                              size_t *out_list_len);
+
+PossumError possum_count_range(const Handle *handle,
+                               PossumKeyBound start,
+                               PossumKeyBound end,
+                               uint64_t *out_count);
+
+/**
+ * Gets the committed changes after seq. The caller must free the keys, non-null from_keys and the
+ * out_list.
+ */
+PossumError possum_changes_since(const Handle *handle,
+                                 ChangeSeq seq,
+                                 PossumChange **out_list,
+                                 size_t *out_list_len);
+
 PossumError possum_single_read_at(const Handle *handle,
                                   PossumBuf key,
                                   PossumBuf *buf,
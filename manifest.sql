@@ -11,10 +11,13 @@ create table keys (
     -- This is the most (concrete?) representation for the finest time granularity sqlite's internal
     -- time functions support.
     last_used integer not null default (cast(unixepoch('subsec')*1e3 as integer)),
+    -- Changes every time a new value is committed for the key. Taken from the generation counter in
+    -- sums so a key that is deleted and recreated never repeats a generation.
+    generation integer not null,
     -- Put this last because it's most likely looked up in the index and not needed when looking at the row.
     key blob unique not null,
-    -- This is necessary for value renames
-    unique (file_id, file_offset)
+    -- Several keys can share a location when they're linked. The value is only punched when the last
+    -- key referencing it is removed.
     check ( iif (
         value_length=0,
         file_id is null and file_offset is null,
@@ -26,22 +29,90 @@ create index if not exists last_used_index on keys (
     key_id
 );
 
--- This is for next_value_offset. Does this duplicate the unique (file_id, file_offset) index on keys?
+-- This is for next_value_offset, value renames, and finding other references to a linked value.
 CREATE INDEX file_id_then_offset on keys (file_id, file_offset);
 -- This is for last_end_offset
 CREATE INDEX file_id_then_end_offset on keys (file_id, file_offset+value_length);
 
+-- Keys that are being fetched by a handle, see the singleflight protocol in DESIGN. Claims older
+-- than the claimant's timeout are considered abandoned.
+create table fetch_claims (
+    key blob primary key,
+    claim_id integer not null,
+    claimed_at integer not null default (cast(unixepoch('subsec')*1e3 as integer))
+) strict, without rowid;
+
+-- Committed mutations, oldest first. This is trimmed to a configured length on commit.
+-- AUTOINCREMENT ensures sequence numbers aren't reused after trimming.
+create table changes (
+    seq integer primary key autoincrement,
+    -- See ChangeOp.
+    op integer not null,
+    key blob not null,
+    -- The previous key for renames.
+    from_key blob,
+    value_length integer not null,
+    last_used integer not null
+) strict;
+
+-- Byte ranges of sparse values that haven't been written yet, relative to the start of the value.
+-- These are keyed by value location so linked keys share them. Values without rows here are
+-- complete.
+create table missing_ranges (
+    file_id integer not null,
+    file_offset integer not null,
+    range_offset integer not null,
+    range_length integer not null,
+    primary key (file_id, file_offset, range_offset)
+) strict, without rowid;
+
+-- Usage of fixed size extents of large values when partial eviction is enabled, see
+-- Limits::partial_eviction_extent_size. Keyed by value location like missing_ranges.
+create table value_extents (
+    file_id integer not null,
+    file_offset integer not null,
+    extent_offset integer not null,
+    extent_length integer not null,
+    last_used integer not null default (cast(unixepoch('subsec')*1e3 as integer)),
+    primary key (file_id, file_offset, extent_offset)
+) strict, without rowid;
+
+create index value_extents_last_used on value_extents (last_used);
+
 create table sums (
     key text primary key,
     value integer not null
 ) strict, without rowid;
 
-insert or ignore into sums values ('value_length', (select coalesce(sum(value_length), 0) from keys));
+insert or ignore into sums values ('value_length', (
+    select coalesce(sum(value_length), 0)
+    from (select distinct file_id, file_offset, value_length from keys)));
+
+insert or ignore into sums values ('generation', 0);
 
-create trigger if not exists value_length_sum_on_delete delete on keys begin
+-- Linked values are only counted once, so the sum reflects the space used by value files.
+create trigger if not exists value_length_sum_on_delete after delete on keys
+when old.file_id is null or not exists (
+    select 1 from keys where file_id=old.file_id and file_offset=old.file_offset)
+begin
     update sums set value=value-old.value_length where key='value_length';
 end;
 
-create trigger if not exists value_length_sum_on_insert insert on keys begin
+-- Missing ranges of sparse or partially evicted values don't take space.
+create trigger if not exists value_length_sum_on_missing_range_insert after insert on missing_ranges
+begin
+    update sums set value=value-new.range_length where key='value_length';
+end;
+
+create trigger if not exists value_length_sum_on_missing_range_delete after delete on missing_ranges
+begin
+    update sums set value=value+old.range_length where key='value_length';
+end;
+
+create trigger if not exists value_length_sum_on_insert after insert on keys
+when new.file_id is null or not exists (
+    select 1 from keys
+    where file_id=new.file_id and file_offset=new.file_offset and key_id!=new.key_id)
+begin
     update sums set value=value+new.value_length where key='value_length';
 end;
\ No newline at end of file
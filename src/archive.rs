@@ -0,0 +1,232 @@
+//! A portable stream of a cache's items, for moving caches between machines and filesystems
+//! without depending on sparse files or block cloning at either end.
+//!
+//! An archive is a header, a record for each item in key order, and an end marker. Integers are
+//! little-endian u64s unless noted.
+//!
+//! - header: ARCHIVE_MAGIC, ARCHIVE_VERSION, item count, value length sum, prefix length, prefix
+//! - record: key length, key, value length, last_used (unix milliseconds as i64), missing range
+//!   count, the offset and length of each missing range, then the bytes of the value outside the
+//!   missing ranges
+//! - end: ARCHIVE_END
+
+use super::*;
+
+/// Starts every archive written by Handle::export.
+pub const ARCHIVE_MAGIC: [u8; 8] = *b"possum\0a";
+/// Bumped when the archive format changes. Only archives of this version can be restored.
+pub const ARCHIVE_VERSION: u64 = 1;
+/// Stands in for a key length after the last record.
+const ARCHIVE_END: u64 = u64::MAX;
+/// Items are exported from a snapshot of this many at a time.
+const EXPORT_PAGE_SIZE: usize = 256;
+/// Restored items are committed this many at a time.
+const RESTORE_BATCH_SIZE: usize = 256;
+
+fn invalid(msg: impl Into<String>) -> Error {
+    Error::InvalidArchive(msg.into())
+}
+
+fn write_u64(writer: &mut impl Write, value: u64) -> io::Result<()> {
+    writer.write_all(&value.to_le_bytes())
+}
+
+fn read_exact(reader: &mut impl Read, buf: &mut [u8]) -> PubResult<()> {
+    reader.read_exact(buf).map_err(|err| match err.kind() {
+        ErrorKind::UnexpectedEof => invalid("archive truncated"),
+        _ => err.into(),
+    })
+}
+
+fn read_u64(reader: &mut impl Read) -> PubResult<u64> {
+    let mut buf = [0; 8];
+    read_exact(reader, &mut buf)?;
+    Ok(u64::from_le_bytes(buf))
+}
+
+fn read_bytes(reader: &mut impl Read, length: u64) -> PubResult<Vec<u8>> {
+    // Don't trust length for the allocation, in case the archive is corrupt.
+    let mut buf = vec![];
+    reader.take(length).read_to_end(&mut buf)?;
+    if buf.len() as u64 != length {
+        return Err(invalid("archive truncated"));
+    }
+    Ok(buf)
+}
+
+/// Copies exactly length bytes from reader to writer.
+fn copy_exact(reader: &mut impl Read, writer: &mut impl Write, length: u64) -> PubResult<()> {
+    if io::copy(&mut reader.take(length), writer)? != length {
+        return Err(invalid("archive truncated"));
+    }
+    Ok(())
+}
+
+fn write_record(
+    writer: &mut impl Write,
+    item: &Item,
+    value: &SnapshotValue<Value>,
+) -> PubResult<()> {
+    let length = value.length();
+    write_u64(writer, item.key.len() as u64)?;
+    writer.write_all(&item.key)?;
+    write_u64(writer, length)?;
+    // The archive keeps when the item was last used before it was exported.
+    let last_used = item.value.last_used().0.and_utc().timestamp_millis();
+    writer.write_all(&last_used.to_le_bytes())?;
+    write_u64(writer, value.missing_ranges().len() as u64)?;
+    for range in value.missing_ranges() {
+        write_u64(writer, range.start)?;
+        write_u64(writer, range.end - range.start)?;
+    }
+    for present in subtract_ranges(0..length, value.missing_ranges()) {
+        let mut src = positioned_io::Cursor::new_pos(value, present.start);
+        if io::copy(&mut (&mut src).take(present.end - present.start), writer)?
+            != present.end - present.start
+        {
+            return Err(anyhow!("value ended during export").into());
+        }
+    }
+    Ok(())
+}
+
+pub(crate) fn export(
+    handle: &Handle,
+    mut writer: impl Write,
+    prefix: &[u8],
+) -> PubResult<BulkOpStats> {
+    // A single transaction is read from throughout, so the items all come from the same view.
+    let mut reader = handle.read()?;
+    let summary = reader.owned_tx.prefix_summary(prefix)?;
+    let stats = BulkOpStats {
+        count: summary.count,
+        value_length_sum: summary.value_length_sum,
+    };
+    writer.write_all(&ARCHIVE_MAGIC)?;
+    write_u64(&mut writer, ARCHIVE_VERSION)?;
+    write_u64(&mut writer, stats.count)?;
+    write_u64(&mut writer, stats.value_length_sum)?;
+    write_u64(&mut writer, prefix.len() as u64)?;
+    writer.write_all(prefix)?;
+    let mut options = ListOptions {
+        prefix: prefix.to_vec(),
+        limit: Some(EXPORT_PAGE_SIZE),
+        ..Default::default()
+    };
+    loop {
+        let items = reader.list_items_page(&options)?;
+        let Some(last) = items.last() else {
+            break;
+        };
+        options.start_after = Some(last.key.clone());
+        let mut values = Vec::with_capacity(items.len());
+        for item in &items {
+            values.push(reader.add(&item.key)?.ok_or_else(|| {
+                anyhow!("listed key {:?} missing in the same transaction", item.key)
+            })?);
+        }
+        // Exporting isn't a use of the items, so their last_used is left alone.
+        let snapshot = reader.snapshot_without_touching()?;
+        for (item, value) in items.iter().zip(values) {
+            write_record(&mut writer, item, &snapshot.value(value))?;
+        }
+    }
+    write_u64(&mut writer, ARCHIVE_END)?;
+    writer.flush()?;
+    Ok(stats)
+}
+
+pub(crate) fn restore(handle: &Handle, mut reader: impl Read) -> PubResult<BulkOpStats> {
+    let mut magic = [0; ARCHIVE_MAGIC.len()];
+    read_exact(&mut reader, &mut magic)?;
+    if magic != ARCHIVE_MAGIC {
+        return Err(invalid("not a possum archive"));
+    }
+    let version = read_u64(&mut reader)?;
+    if version != ARCHIVE_VERSION {
+        return Err(invalid(format!("unsupported archive version {version}")));
+    }
+    let expected = BulkOpStats {
+        count: read_u64(&mut reader)?,
+        value_length_sum: read_u64(&mut reader)?,
+    };
+    let prefix_length = read_u64(&mut reader)?;
+    let prefix = read_bytes(&mut reader, prefix_length)?;
+    let mut stats = BulkOpStats::default();
+    let mut writer = handle.new_writer()?;
+    let mut staged = 0;
+    loop {
+        let key_length = read_u64(&mut reader)?;
+        if key_length == ARCHIVE_END {
+            break;
+        }
+        let key = read_bytes(&mut reader, key_length)?;
+        if !key.starts_with(&prefix) {
+            return Err(invalid(format!(
+                "key {key:?} is outside the archive prefix"
+            )));
+        }
+        let length = read_u64(&mut reader)?;
+        let mut last_used = [0; 8];
+        read_exact(&mut reader, &mut last_used)?;
+        let last_used = TimestampInner::from_timestamp_millis(i64::from_le_bytes(last_used))
+            .map(Timestamp)
+            .ok_or_else(|| invalid("last_used out of range"))?;
+        let missing_count = read_u64(&mut reader)?;
+        let mut missing = vec![];
+        for _ in 0..missing_count {
+            let offset = read_u64(&mut reader)?;
+            let range = offset
+                ..offset
+                    .checked_add(read_u64(&mut reader)?)
+                    .ok_or_else(|| invalid("missing range overflows"))?;
+            let after_previous = missing
+                .last()
+                .is_none_or(|prev: &Range<u64>| prev.end <= offset);
+            if range.is_empty() || range.end > length || !after_previous {
+                return Err(invalid(format!(
+                    "bad missing range {range:?} for key {key:?}"
+                )));
+            }
+            missing.push(range);
+        }
+        let present = subtract_ranges(0..length, &missing);
+        if present.is_empty() && length != 0 {
+            writer.stage_sparse(key, length)?;
+        } else if missing.is_empty() {
+            let mut value = writer.new_value().begin()?;
+            copy_exact(&mut reader, &mut value, length)?;
+            writer.stage_write(key, value)?;
+        } else {
+            let mut value = writer.new_value().begin_with_size(length)?;
+            let mut buf = vec![];
+            for range in present {
+                let mut offset = range.start;
+                while offset < range.end {
+                    let chunk = min(range.end - offset, 1 << 16);
+                    buf.resize(chunk as usize, 0);
+                    read_exact(&mut reader, &mut buf)?;
+                    value.write_at(offset, &buf)?;
+                    offset += chunk;
+                }
+            }
+            writer.stage_incomplete(key, value)?;
+        }
+        writer.set_staged_last_used(last_used);
+        stats.count += 1;
+        stats.value_length_sum += length;
+        staged += 1;
+        if staged == RESTORE_BATCH_SIZE {
+            writer.commit()?;
+            writer = handle.new_writer()?;
+            staged = 0;
+        }
+    }
+    if stats != expected {
+        return Err(invalid(format!(
+            "archive header promised {expected:?}, found {stats:?}"
+        )));
+    }
+    writer.commit()?;
+    Ok(stats)
+}
@@ -98,6 +98,99 @@ pub extern "C" fn possum_list_items(
     NoError
 }
 
+/// Lists up to limit items with prefix in key order. A limit of 0 is rejected with IoError, as it
+/// would never make progress; pass SIZE_MAX for no limit. Like the returned keys, start_after is
+/// relative to prefix, and listing continues after it unless its ptr is null. Pass the last key of
+/// the previous page to get the next page.
+#[no_mangle]
+pub extern "C" fn possum_list_items_page(
+    handle: *const Handle,
+    prefix: PossumBuf,
+    start_after: PossumBuf,
+    limit: size_t,
+    reverse: bool,
+    out_list: *mut *mut PossumItem,
+    out_list_len: *mut size_t,
+) -> PossumError {
+    if limit == 0 {
+        return io::Error::new(io::ErrorKind::InvalidInput, "page limit must be nonzero").into();
+    }
+    let options = ListOptions {
+        prefix: prefix.as_ref().to_vec(),
+        start_after: (!start_after.ptr.is_null())
+            .then(|| [prefix.as_ref(), start_after.as_ref()].concat()),
+        limit: Some(limit),
+        reverse,
+    };
+    let items = match unsafe { handle.as_ref() }
+        .unwrap()
+        .list_items_page(&options)
+    {
+        Ok(items) => items,
+        Err(err) => return err.into(),
+    };
+    items_list_to_c(prefix.size, items, out_list, out_list_len);
+    NoError
+}
+
+/// Lists up to limit items with keys between start and end, or all of them if limit is 0, in
+/// descending order if reverse is set.
+#[no_mangle]
+pub extern "C" fn possum_list_range(
+    handle: *const Handle,
+    start: PossumKeyBound,
+    end: PossumKeyBound,
+    reverse: bool,
+    limit: size_t,
+    out_list: *mut *mut PossumItem,
+    out_list_len: *mut size_t,
+) -> PossumError {
+    let items = match unsafe { handle.as_ref() }.unwrap().list_range(
+        (start.as_bound(), end.as_bound()),
+        reverse,
+        (limit != 0).then_some(limit),
+    ) {
+        Ok(items) => items,
+        Err(err) => return err.into(),
+    };
+    items_list_to_c(0, items, out_list, out_list_len);
+    NoError
+}
+
+#[no_mangle]
+pub extern "C" fn possum_count_range(
+    handle: *const Handle,
+    start: PossumKeyBound,
+    end: PossumKeyBound,
+    out_count: *mut u64,
+) -> PossumError {
+    match unsafe { handle.as_ref() }
+        .unwrap()
+        .count_range((start.as_bound(), end.as_bound()))
+    {
+        Ok(count) => unsafe { *out_count = count },
+        Err(err) => return err.into(),
+    }
+    NoError
+}
+
+/// Gets the committed changes after seq. The caller must free the keys, non-null from_keys and the
+/// out_list.
+#[no_mangle]
+pub extern "C" fn possum_changes_since(
+    handle: *const Handle,
+    seq: ChangeSeq,
+    out_list: *mut *mut PossumChange,
+    out_list_len: *mut size_t,
+) -> PossumError {
+    let changes = match unsafe { handle.as_ref() }.unwrap().changes_since(seq) {
+        Ok(changes) => changes,
+        Err(err) => return err.into(),
+    };
+    changes_list_to_c(changes, out_list, out_list_len);
+    NoError
+}
+
 #[no_mangle]
 pub extern "C" fn possum_single_read_at(
     handle: *const Handle,
@@ -167,11 +260,7 @@ pub extern "C" fn possum_handle_move_prefix(
     to: PossumBuf,
 ) -> PossumError {
     let handle = unsafe { &mut *handle };
-    with_residual(|| {
-        handle
-            .move_prefix(from.as_ref(), to.as_ref())
-            .map_err(Into::into)
-    })
+    with_residual(|| handle.move_prefix(from.as_ref(), to.as_ref()).map(|_| ()))
 }
 
 #[no_mangle]
@@ -180,5 +269,5 @@ pub extern "C" fn possum_handle_delete_prefix(
     prefix: PossumBuf,
 ) -> PossumError {
     let handle = unsafe { &mut *handle };
-    with_residual(|| handle.delete_prefix(prefix.as_ref()).map_err(Into::into))
+    with_residual(|| handle.delete_prefix(prefix.as_ref()).map(|_| ()))
 }
@@ -49,6 +49,20 @@ pub extern "C" fn possum_start_new_value(
     })
 }
 
+#[no_mangle]
+pub extern "C" fn possum_start_new_value_with_size(
+    writer: *mut PossumWriter,
+    length: u64,
+    value: *mut *mut PossumValueWriter,
+) -> PossumError {
+    let writer = unsafe { &mut *writer };
+    with_residual(|| {
+        let v = Box::into_raw(Box::new(writer.new_value().begin_with_size(length)?));
+        unsafe { *value = v };
+        Ok(())
+    })
+}
+
 #[no_mangle]
 pub extern "C" fn possum_value_writer_fd(value: *mut PossumValueWriter) -> RawFileHandle {
     unsafe { &mut *value }
@@ -167,6 +181,53 @@ pub extern "C" fn possum_reader_list_items(
     })
 }
 
+/// Lists up to limit items with keys between start and end as of the reader's transaction, or all
+/// of them if limit is 0, in descending order if reverse is set.
+#[no_mangle]
+pub extern "C" fn possum_reader_list_range(
+    reader: *const PossumReader,
+    start: PossumKeyBound,
+    end: PossumKeyBound,
+    reverse: bool,
+    limit: size_t,
+    out_items: *mut *mut PossumItem,
+    out_len: *mut size_t,
+) -> PossumError {
+    let reader = unsafe { &*reader };
+    with_residual(|| {
+        items_list_to_c(
+            0,
+            reader.rust_reader.as_ref().unwrap().list_range(
+                (start.as_bound(), end.as_bound()),
+                reverse,
+                (limit != 0).then_some(limit),
+            )?,
+            out_items,
+            out_len,
+        );
+        Ok(())
+    })
+}
+
+#[no_mangle]
+pub extern "C" fn possum_reader_count_range(
+    reader: *const PossumReader,
+    start: PossumKeyBound,
+    end: PossumKeyBound,
+    out_count: *mut u64,
+) -> PossumError {
+    let reader = unsafe { &*reader };
+    with_residual(|| {
+        let count = reader
+            .rust_reader
+            .as_ref()
+            .unwrap()
+            .count_range((start.as_bound(), end.as_bound()))?;
+        unsafe { *out_count = count };
+        Ok(())
+    })
+}
+
 #[no_mangle]
 pub extern "C" fn possum_writer_commit(writer: *mut PossumWriter) -> PossumError {
     let writer = unsafe { Box::from_raw(writer) };
@@ -187,6 +248,116 @@ pub extern "C" fn possum_writer_stage(
     with_residual(|| {
         writer
             .stage_write(key.as_ref().to_vec(), *value)
-            .map_err(Into::into)
+            .map_err(downcast_stage_error)
+    })
+}
+
+/// Stages a value that may not have been entirely written with possum_value_writer_write_at.
+#[no_mangle]
+pub extern "C" fn possum_writer_stage_incomplete(
+    writer: *mut PossumWriter,
+    key: PossumBuf,
+    value: *mut PossumValueWriter,
+) -> PossumError {
+    let writer = unsafe { &mut *writer };
+    let value = unsafe { Box::from_raw(value) };
+    with_residual(|| {
+        writer
+            .stage_incomplete(key.as_ref().to_vec(), *value)
+            .map_err(downcast_stage_error)
+    })
+}
+
+fn downcast_stage_error(err: anyhow::Error) -> Error {
+    match err.downcast::<Error>() {
+        Ok(err) => err,
+        Err(err) => err.into(),
+    }
+}
+
+/// Writes buf at offset in a value started with possum_start_new_value_with_size.
+#[no_mangle]
+pub extern "C" fn possum_value_writer_write_at(
+    value: *mut PossumValueWriter,
+    offset: u64,
+    buf: PossumBuf,
+) -> PossumError {
+    let value = unsafe { &mut *value };
+    with_residual(|| value.write_at(offset, buf.as_ref()))
+}
+
+/// Appends bufs to the value, setting written to the number of bytes written. Like writev, this may
+/// write less than all of bufs.
+#[no_mangle]
+pub extern "C" fn possum_value_writer_write_vectored(
+    value: *mut PossumValueWriter,
+    bufs: *const PossumBuf,
+    bufs_len: size_t,
+    written: *mut size_t,
+) -> PossumError {
+    let value = unsafe { &mut *value };
+    let bufs = unsafe { slice::from_raw_parts(bufs, bufs_len) };
+    let io_slices: Vec<_> = bufs
+        .iter()
+        .map(|buf| io::IoSlice::new(buf.as_ref()))
+        .collect();
+    match value.write_vectored(&io_slices) {
+        Ok(n) => {
+            unsafe { *written = n };
+            NoError
+        }
+        Err(err) => err.into(),
+    }
+}
+
+/// Appends length bytes from file at offset to the value, without passing through user space where
+/// supported. copied is set to the number of bytes copied, which is less than length if the file
+/// ends first.
+#[no_mangle]
+pub extern "C" fn possum_value_writer_copy_from_file(
+    value: *mut PossumValueWriter,
+    file: RawFileHandle,
+    offset: u64,
+    length: u64,
+    copied: *mut u64,
+) -> PossumError {
+    let value = unsafe { &mut *value };
+    let file = borrow_raw_file_handle(file);
+    with_residual(|| {
+        let n = value.copy_from_file(&file, offset, length)?;
+        unsafe { *copied = n };
+        Ok(())
     })
 }
+
+/// Sends the value from offset to fd, which can be a file, pipe or socket, with splice or sendfile
+/// where supported. sent is set to the number of bytes sent, which can be fewer than the rest of
+/// the value if fd is nonblocking. Only supported on Unix.
+#[no_mangle]
+pub extern "C" fn possum_value_send_to(
+    value: *const PossumValue,
+    offset: u64,
+    fd: RawFileHandle,
+    sent: *mut u64,
+) -> PossumError {
+    let value = unsafe { &*value };
+    let PossumValue::SnapshotValue(value) = value else {
+        panic!("reader snapshot must be taken");
+    };
+    let dst = borrow_raw_file_handle(fd);
+    cfg_if! {
+        if #[cfg(unix)] {
+            let res = value.send_to(offset, &*dst);
+        } else {
+            let _ = (value, offset, dst);
+            let res: io::Result<u64> = Err(io::ErrorKind::Unsupported.into());
+        }
+    }
+    match res {
+        Ok(n) => {
+            unsafe { *sent = n };
+            NoError
+        }
+        Err(err) => err.into(),
+    }
+}
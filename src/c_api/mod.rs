@@ -6,7 +6,8 @@ mod ext_fns;
 mod types;
 
 use std::ffi::c_char;
-use std::mem::size_of;
+use std::mem::{size_of, ManuallyDrop};
+use std::ops::Bound::{self, Excluded, Included, Unbounded};
 use std::pin::Pin;
 use std::ptr::copy_nonoverlapping;
 use std::slice;
@@ -29,6 +30,16 @@ impl PossumBuf {
     }
 }
 
+impl PossumKeyBound {
+    fn as_bound(&self) -> Bound<&[u8]> {
+        match self.kind {
+            PossumBoundKind::Unbounded => Unbounded,
+            PossumBoundKind::Included => Included(self.key.as_ref()),
+            PossumBoundKind::Excluded => Excluded(self.key.as_ref()),
+        }
+    }
+}
+
 struct PossumReader {
     // Removed when converted to a snapshot. Specific to the C API so as to not need to expose
     // Snapshot, and to convert Values automatically when a snapshot starts.
@@ -97,9 +108,57 @@ fn items_list_to_c(
     }
 }
 
-use PossumError::*;
+/// Copies bytes to a buffer allocated with malloc, which the caller must free. The pointer is never
+/// null, even for empty bytes, so it can't be mistaken for an absent buffer.
+fn malloc_buf(bytes: &[u8]) -> PossumBuf {
+    // malloc(0) can return null.
+    let ptr = unsafe { malloc(bytes.len().max(1)) } as *mut u8;
+    if ptr.is_null() {
+        std::alloc::handle_alloc_error(std::alloc::Layout::array::<u8>(bytes.len()).unwrap());
+    }
+    if !bytes.is_empty() {
+        unsafe { copy_nonoverlapping(bytes.as_ptr(), ptr, bytes.len()) };
+    }
+    PossumBuf {
+        ptr: ptr as *const c_char,
+        size: bytes.len(),
+    }
+}
+
+/// Converts Changes to C PossumChanges. The caller must free the keys, the from_keys that aren't
+/// null, and the out_list.
+fn changes_list_to_c(
+    changes: Vec<Change>,
+    out_list: *mut *mut PossumChange,
+    out_list_len: *mut size_t,
+) {
+    unsafe {
+        *out_list = calloc(size_of::<PossumChange>(), changes.len()) as *mut PossumChange;
+        *out_list_len = changes.len();
+    }
+    for (index, change) in changes.iter().enumerate() {
+        let c_change = PossumChange {
+            seq: change.seq,
+            op: change.op,
+            key: malloc_buf(&change.key),
+            from_key: match &change.from_key {
+                Some(from_key) => malloc_buf(from_key),
+                None => PossumBuf {
+                    ptr: std::ptr::null(),
+                    size: 0,
+                },
+            },
+            stat: PossumStat {
+                last_used: change.last_used.into(),
+                size: change.value_length,
+            },
+        };
+        let dest = unsafe { (*out_list).add(index) };
+        unsafe { *dest = c_change };
+    }
+}
 
-use crate::item::Item;
+use PossumError::*;
 
 impl From<Error> for PossumError {
     fn from(value: Error) -> Self {
@@ -109,6 +168,12 @@ impl From<Error> for PossumError {
             Error::Io(_) => IoError,
             Error::Anyhow(_) => AnyhowError,
             Error::UnsupportedFilesystem => UnsupportedFilesystem,
+            Error::WriteConflict { .. } => WriteConflict,
+            Error::ValueLengthMismatch { .. } => ValueLengthMismatch,
+            Error::IncompleteValue { .. } => IncompleteValue,
+            Error::InvalidArchive(_) => InvalidArchive,
+            Error::NoSuchSnapshot => NoSuchSnapshot,
+            Error::ChangesDropped => ChangesDropped,
         }
     }
 }
@@ -149,6 +214,8 @@ impl From<PossumLimits> for handle::Limits {
                 otherwise => Some(otherwise),
             },
             disable_hole_punching: from.disable_hole_punching,
+            max_change_log_len: None,
+            partial_eviction_extent_size: None,
         }
     }
 }
@@ -170,3 +237,16 @@ impl AsRawFileHandle for File {
         }
     }
 }
+
+/// Wraps a RawFileHandle from the C API as a File, without taking ownership of it.
+fn borrow_raw_file_handle(handle: RawFileHandle) -> ManuallyDrop<File> {
+    cfg_if! {
+        if #[cfg(windows)] {
+            use std::os::windows::io::FromRawHandle;
+            ManuallyDrop::new(unsafe { File::from_raw_handle(handle as _) })
+        } else {
+            use std::os::fd::FromRawFd;
+            ManuallyDrop::new(unsafe { File::from_raw_fd(handle as _) })
+        }
+    }
+}
@@ -5,7 +5,7 @@ use std::ffi::c_char;
 use libc::size_t;
 pub(crate) use value::*;
 
-use crate::{BatchWriter, ValueWriter};
+use crate::{BatchWriter, ChangeOp, ChangeSeq, ValueWriter};
 
 pub(crate) type PossumOffset = u64;
 
@@ -37,6 +37,32 @@ pub struct PossumItem {
     pub stat: PossumStat,
 }
 
+// Only constructed by C callers.
+#[allow(dead_code)]
+#[repr(C)]
+pub enum PossumBoundKind {
+    Unbounded,
+    Included,
+    Excluded,
+}
+
+/// One end of a key range. The key is ignored if the bound is Unbounded.
+#[repr(C)]
+pub struct PossumKeyBound {
+    pub kind: PossumBoundKind,
+    pub key: PossumBuf,
+}
+
+#[repr(C)]
+pub struct PossumChange {
+    pub seq: ChangeSeq,
+    pub op: ChangeOp,
+    pub key: PossumBuf,
+    /// Null if the change isn't a rename.
+    pub from_key: PossumBuf,
+    pub stat: PossumStat,
+}
+
 #[repr(C)]
 pub enum PossumError {
     NoError,
@@ -45,6 +71,12 @@ pub enum PossumError {
     IoError,
     AnyhowError,
     UnsupportedFilesystem,
+    WriteConflict,
+    ValueLengthMismatch,
+    IncompleteValue,
+    InvalidArchive,
+    NoSuchSnapshot,
+    ChangesDropped,
 }
 // TODO: Merge the C and Rust error types.
 // pub use crate::Error as PossumError;
@@ -0,0 +1,68 @@
+//! The change log of committed mutations.
+
+use super::*;
+
+/// Sequence number of a committed mutation. These increase monotonically and are never reused.
+pub type ChangeSeq = u64;
+
+/// The kind of mutation recorded in the change log.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(C)]
+pub enum ChangeOp {
+    /// A new value was committed for the key.
+    Write = 1,
+    /// The value was given a new key. The key it had before is in Change::from_key.
+    Rename = 2,
+    /// The key was deleted.
+    Delete = 3,
+    /// The key was evicted to satisfy limits.
+    Evict = 4,
+}
+
+impl FromSql for ChangeOp {
+    fn column_result(value: ValueRef<'_>) -> FromSqlResult<Self> {
+        use ChangeOp::*;
+        Ok(match value.as_i64()? {
+            1 => Write,
+            2 => Rename,
+            3 => Delete,
+            4 => Evict,
+            other => return Err(FromSqlError::OutOfRange(other)),
+        })
+    }
+}
+
+impl ToSql for ChangeOp {
+    fn to_sql(&self) -> rusqlite::Result<ToSqlOutput<'_>> {
+        Ok((*self as i64).into())
+    }
+}
+
+/// A committed mutation, and the stat of the value it applied to.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Change {
+    pub seq: ChangeSeq,
+    pub op: ChangeOp,
+    pub key: Vec<u8>,
+    pub from_key: Option<Vec<u8>>,
+    pub value_length: u64,
+    pub last_used: Timestamp,
+}
+
+pub(crate) const CHANGE_COLUMNS_SQL: &str = "seq, op, key, from_key, value_length, last_used";
+
+impl Change {
+    pub(crate) fn from_row(row: &rusqlite::Row) -> rusqlite::Result<Self> {
+        Ok(Self {
+            seq: row.get(0)?,
+            op: row.get(1)?,
+            key: row.get(2)?,
+            from_key: row.get(3)?,
+            value_length: row.get(4)?,
+            last_used: row.get(5)?,
+        })
+    }
+}
+
+/// The number of changes kept if Limits::max_change_log_len isn't set.
+pub const DEFAULT_MAX_CHANGE_LOG_LEN: u64 = 1 << 16;
@@ -17,6 +17,22 @@ pub enum Error {
     Anyhow(#[from] anyhow::Error),
     #[error("unsupported filesystem")]
     UnsupportedFilesystem,
+    #[error("write conflict on key {key:?}: expected generation {expected:?}, found {actual:?}")]
+    WriteConflict {
+        key: Vec<u8>,
+        expected: Option<Generation>,
+        actual: Option<Generation>,
+    },
+    #[error("value length mismatch: reserved {expected}, wrote {actual}")]
+    ValueLengthMismatch { expected: u64, actual: u64 },
+    #[error("value incomplete: missing {missing:?}")]
+    IncompleteValue { missing: Vec<std::ops::Range<u64>> },
+    #[error("invalid archive: {0}")]
+    InvalidArchive(String),
+    #[error("no such snapshot")]
+    NoSuchSnapshot,
+    #[error("changes were dropped from the change log before they were seen")]
+    ChangesDropped,
 }
 
 use Error::*;
@@ -45,7 +61,13 @@ impl FileAlreadyExistsError for Error {
 impl Error {
     pub fn root_cause(&self) -> &(dyn std::error::Error + 'static) {
         match self {
-            NoSuchKey | UnsupportedFilesystem => self,
+            NoSuchKey
+            | UnsupportedFilesystem
+            | WriteConflict { .. }
+            | ValueLengthMismatch { .. }
+            | IncompleteValue { .. }
+            | InvalidArchive(_)
+            | NoSuchSnapshot => self,
             Sqlite(inner) => inner,
             Anyhow(inner) => inner.root_cause(),
             _ => unimplemented!(),
@@ -19,6 +19,7 @@ use LockLevel::*;
 pub(crate) struct ExclusiveFile {
     pub(crate) inner: File,
     pub(crate) id: FileId,
+    pub(crate) path: PathBuf,
     last_committed_offset: u64,
     lock_level: LockLevel,
 }
@@ -26,7 +27,17 @@ pub(crate) struct ExclusiveFile {
 impl ExclusiveFile {
     pub(crate) fn open(path: PathBuf) -> Result<Option<Self>> {
         let file = Self::new_open_options().open(&path)?;
-        Self::from_file(file, path.file_name().context("file name")?.try_into()?)
+        let id = path.file_name().context("file name")?.try_into()?;
+        Self::from_file(file, id, path)
+    }
+
+    /// Opens a file that's to become a values file at path, locking all of it so its existing
+    /// contents can be committed as a new value. The lock is taken before the file appears at
+    /// path, so no other writer can claim the end of it first.
+    pub(crate) fn open_uncommitted(src: &Path, path: PathBuf) -> Result<Option<Self>> {
+        let file = Self::new_open_options().open(src)?;
+        let id = path.file_name().context("file name")?.try_into()?;
+        Self::lock_from(file, id, path, 0)
     }
 
     fn new_open_options() -> OpenOptions {
@@ -51,33 +62,108 @@ impl ExclusiveFile {
         self.inner.set_len(offset)
     }
 
+    /// Grows the file by length without writing anything, leaving a hole.
+    pub(crate) fn extend(&mut self, length: u64) -> io::Result<()> {
+        let end = self.next_write_offset()? + length;
+        self.inner.set_len(end)?;
+        self.inner.seek(Start(end))?;
+        Ok(())
+    }
+
+    /// Allocates space for the next length bytes to be written, without changing the file length.
+    pub(crate) fn preallocate(&mut self, length: u64) -> io::Result<()> {
+        let offset = self.next_write_offset()?;
+        let res = preallocate(&self.inner, offset, length);
+        // A failed preallocation can still leave blocks allocated past the end of the file. Invalid
+        // ranges fail before allocating anything.
+        if matches!(&res, Err(err) if err.kind() != ErrorKind::InvalidInput) {
+            if let Err(err) = punchfile(&self.inner, offset, length) {
+                error!("error releasing failed preallocation: {:#?}", err);
+            }
+        }
+        res
+    }
+
+    /// Clones all of src onto the end of the file, after padding the file to a multiple of
+    /// block_size as block cloning requires. Returns the offset src was cloned to.
+    pub(crate) fn clone_range_in(&mut self, src: &File, block_size: u64) -> io::Result<u64> {
+        let length = src.metadata()?.len();
+        self.clone_range_from(src, 0, length, block_size)
+    }
+
+    /// Like clone_range_in, but clones only length bytes of src from src_offset, which must be
+    /// block-aligned.
+    pub(crate) fn clone_range_from(
+        &mut self,
+        src: &File,
+        src_offset: u64,
+        length: u64,
+        block_size: u64,
+    ) -> io::Result<u64> {
+        let start = self.next_write_offset()?;
+        if length == 0 {
+            return Ok(start);
+        }
+        let offset = ceil_multiple(start, block_size);
+        let res = (|| {
+            // Some platforms only clone into ranges that are already within the file.
+            self.extend(offset - start + length)?;
+            // Cloning into a file opened for appending isn't allowed.
+            let dst = OpenOptions::new().write(true).open(&self.path)?;
+            clone_file_range(src, src_offset, &dst, offset, length)?;
+            Ok(offset)
+        })();
+        if res.is_err() {
+            if let Err(err) = self.revert_to_offset(start) {
+                error!("error reverting range clone: {:#?}", err);
+            }
+        }
+        res
+    }
+
     pub(crate) fn new(dir: impl AsRef<Path>) -> anyhow::Result<ExclusiveFile> {
         for _ in 0..10 {
             let id = FileId::random();
             let path = dir.as_ref().join(id.values_file_path());
             debug!(?path, "opening new exclusive file");
-            let file = Self::new_open_options().create(true).open(path);
+            let file = Self::new_open_options().create(true).open(&path);
             let file = match file {
                 Ok(file) => file,
                 Err(err) => return Err(err.into()),
             };
-            if let Some(exclusive_file) = Self::from_file(file, id)? {
+            if let Some(exclusive_file) = Self::from_file(file, id, path)? {
                 return Ok(exclusive_file);
             }
         }
         bail!("gave up trying to create exclusive file")
     }
 
-    pub(crate) fn from_file(mut file: File, id: FileId) -> anyhow::Result<Option<ExclusiveFile>> {
+    pub(crate) fn from_file(
+        mut file: File,
+        id: FileId,
+        path: PathBuf,
+    ) -> anyhow::Result<Option<ExclusiveFile>> {
         let end = file.seek(End(0))?;
-        if !file.lock_segment(LockExclusiveNonblock, None, end)? {
+        Self::lock_from(file, id, path, end)
+    }
+
+    /// Locks file exclusively from offset onwards, which is where uncommitted writes start.
+    fn lock_from(
+        mut file: File,
+        id: FileId,
+        path: PathBuf,
+        offset: u64,
+    ) -> anyhow::Result<Option<ExclusiveFile>> {
+        if !file.lock_segment(LockExclusiveNonblock, None, offset)? {
             return Ok(None);
         }
+        file.seek(End(0))?;
         file.set_sparse(true)?;
         Ok(Some(ExclusiveFile {
             inner: file,
             id,
-            last_committed_offset: end,
+            path,
+            last_committed_offset: offset,
             lock_level: Exclusive,
         }))
     }
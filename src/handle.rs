@@ -1,3 +1,5 @@
+use std::ops::RangeBounds;
+
 use rusqlite::TransactionBehavior;
 
 use super::*;
@@ -8,9 +10,25 @@ pub struct Limits {
     pub max_value_length_sum: Option<u64>,
     // Invert this logic when there are defaults and mutators.
     pub disable_hole_punching: bool,
+    /// The number of committed changes to keep for Handle::changes_since. Defaults to
+    /// DEFAULT_MAX_CHANGE_LOG_LEN.
+    pub max_change_log_len: Option<u64>,
+    /// Values larger than this have their usage tracked in extents of this size, rounded up to
+    /// the block size. Eviction then punches the coldest extents, leaving the value partially
+    /// present, instead of evicting whole values. Use Reader::add_range to read parts of values
+    /// without keeping the rest warm.
+    pub partial_eviction_extent_size: Option<u64>,
 }
 
-type DeletedValuesSender = std::sync::mpsc::SyncSender<Vec<NonzeroValueLocation>>;
+/// How often a handle checks for a value that another handle has claimed to fetch.
+const FETCH_CLAIM_POLL_INTERVAL: Duration = Duration::from_millis(10);
+
+/// rusqlite's busy timeout for new connections, restored after operations that don't wait.
+const DEFAULT_BUSY_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Value file regions for the value puncher, and how far each can be expanded.
+type PendingPunches = Vec<(NonzeroValueLocation, PunchValueConstraints)>;
+type DeletedValuesSender = std::sync::mpsc::SyncSender<PendingPunches>;
 
 /// Provides access to a storage directory. Manages manifest access, file cloning, file writers,
 /// configuration, value eviction etc.
@@ -91,7 +109,7 @@ impl Handle {
     }
 
     // Expected manifest sqlite user version field value.
-    const USER_VERSION: u32 = 3;
+    const USER_VERSION: u32 = 9;
 
     pub fn new(dir: PathBuf) -> Result<Self> {
         let sqlite_version = rusqlite::version_number();
@@ -204,8 +222,29 @@ impl Handle {
         Ok(())
     }
 
+    /// Removes snapshot files that are no longer in use, and named snapshots that have expired.
     pub fn cleanup_snapshots(&self) -> PubResult<()> {
-        delete_unused_snapshots(self.dir.path()).map_err(Into::into)
+        delete_unused_snapshots(self.dir.path())?;
+        named_snapshot::delete_expired(self.dir.path()).map_err(Into::into)
+    }
+
+    /// Takes a snapshot of the whole cache that persists until it's released, or until ttl has
+    /// passed and Handle::cleanup_snapshots runs. Any Handle on the cache directory, in any
+    /// process, can open it by name. It costs about as much as Handle::backup_to. Names are
+    /// limited to ASCII letters, digits, '-', '_' and '.', and can't already be taken.
+    pub fn create_named_snapshot(&self, name: &str, ttl: Option<Duration>) -> PubResult<()> {
+        named_snapshot::create(self, name, ttl)
+    }
+
+    /// Opens a named snapshot for reading. Returns Error::NoSuchSnapshot if it doesn't exist or
+    /// has expired.
+    pub fn open_named_snapshot(&self, name: &str) -> PubResult<NamedSnapshot> {
+        NamedSnapshot::open(self, name)
+    }
+
+    /// Removes a named snapshot. Returns false if there was no such snapshot.
+    pub fn release_named_snapshot(&self, name: &str) -> PubResult<bool> {
+        named_snapshot::release(self, name)
     }
 
     pub fn block_size(&self) -> u64 {
@@ -218,6 +257,9 @@ impl Handle {
             exclusive_files: Default::default(),
             pending_writes: Default::default(),
             value_renames: Default::default(),
+            links: Default::default(),
+            preconditions: Default::default(),
+            released_fetch_claims: Default::default(),
         })
     }
 
@@ -263,16 +305,53 @@ impl Handle {
         self.start_writable_transaction_with_behaviour(TransactionBehavior::Deferred)
     }
 
-    /// Begins a read transaction.
+    /// Begins a read transaction. It doesn't take the manifest write lock, so readers don't hold
+    /// up writers.
     pub fn read(&self) -> rusqlite::Result<Reader> {
+        // This must be held before the transaction starts, or values it sees could already be
+        // getting punched. Failing to take it is reported as an I/O error from sqlite to keep the
+        // signature.
+        let clone_lock = lock_clone_lock_for_read(self.dir.path()).map_err(|err| {
+            rusqlite::Error::SqliteFailure(
+                rusqlite::ffi::Error::new(rusqlite::ffi::SQLITE_IOERR),
+                Some(format!("locking clone lock: {err}")),
+            )
+        })?;
         let reader = Reader {
-            owned_tx: self.start_deferred_transaction()?,
+            owned_tx: self.start_deferred_transaction_for_read()?,
             handle: self,
             reads: Default::default(),
+            missing_ranges: Default::default(),
+            clone_lock,
+            touches: vec![],
         };
         Ok(reader)
     }
 
+    /// Updates last_used for keys that were read, unless they've been written since.
+    pub(crate) fn touch_for_read(&self, touches: Vec<reader::Touch>) -> Result<()> {
+        if touches.is_empty() {
+            return Ok(());
+        }
+        let mut conn = self.conn.lock().unwrap();
+        // Reads shouldn't wait behind writers just to update last_used, so this makes a single
+        // attempt. The connection is held throughout so nothing else sees the zero timeout.
+        conn.busy_timeout(Duration::ZERO)?;
+        let result = (|| {
+            let mut tx = Transaction::new(
+                conn.transaction_with_behavior(TransactionBehavior::Immediate)?,
+                self,
+            );
+            for touch in touches {
+                tx.touch_range_for_read(&touch.key, touch.generation, touch.range)?;
+            }
+            tx.commit(())
+        })()
+        .map(PostCommitWork::complete);
+        conn.busy_timeout(DEFAULT_BUSY_TIMEOUT)?;
+        result
+    }
+
     pub fn read_single(&self, key: &[u8]) -> Result<Option<SnapshotValue<Value>>> {
         let mut reader = self.read()?;
         let Some(value) = reader.add(key)? else {
@@ -296,6 +375,66 @@ impl Handle {
         Ok((n, commit))
     }
 
+    /// Returns the value for key, fetching it if it's missing. Only one caller across all handles
+    /// on the directory fetches a missing key at a time, the others poll until the value appears.
+    /// If the claim on the fetch is older than claim_timeout, it's assumed the claimant crashed,
+    /// and the fetch is taken over. Fails if the fetched value is removed before it can be read.
+    pub fn get_or_fetch(
+        &self,
+        key: &[u8],
+        fetch: impl FnOnce(&mut ValueWriter) -> anyhow::Result<()>,
+        claim_timeout: Duration,
+    ) -> PubResult<SnapshotValue<Value>> {
+        loop {
+            if let Some(value) = self.read_single(key)? {
+                return Ok(value);
+            }
+            let claim_id = FetchClaimId::random();
+            let mut tx = self.start_immediate_transaction()?;
+            // The key could have been written since we tried to read it.
+            let claimed = tx.key_generation(key)?.is_none()
+                && tx.try_claim_fetch(key, claim_id, claim_timeout)?;
+            tx.commit(())?.complete();
+            if !claimed {
+                std::thread::sleep(FETCH_CLAIM_POLL_INTERVAL);
+                continue;
+            }
+            if let Err(err) = self.fetch_claimed(key, claim_id, fetch) {
+                if let Err(release_err) = self.release_fetch_claim(key, claim_id) {
+                    error!("releasing fetch claim after failed fetch: {release_err:#}");
+                }
+                return Err(err);
+            }
+            // The fetch can only run once, so there's no going round again if the value was
+            // deleted or evicted before it could be read.
+            return self.read_single(key)?.ok_or_else(|| {
+                anyhow!("fetched value for {key:?} was removed before it could be read").into()
+            });
+        }
+    }
+
+    fn fetch_claimed(
+        &self,
+        key: &[u8],
+        claim_id: FetchClaimId,
+        fetch: impl FnOnce(&mut ValueWriter) -> anyhow::Result<()>,
+    ) -> PubResult<()> {
+        let mut writer = self.new_writer()?;
+        let mut value = writer.new_value().begin()?;
+        fetch(&mut value)?;
+        writer.stage_write(key.to_vec(), value)?;
+        writer.release_fetch_claim(key.to_vec(), claim_id);
+        writer.commit()?;
+        Ok(())
+    }
+
+    fn release_fetch_claim(&self, key: &[u8], claim_id: FetchClaimId) -> PubResult<()> {
+        let mut tx = self.start_immediate_transaction()?;
+        tx.release_fetch_claim(key, claim_id)?;
+        tx.commit(())?.complete();
+        Ok(())
+    }
+
     pub fn single_delete(&self, key: &[u8]) -> PubResult<Option<c_api::PossumStat>> {
         let mut tx = self.start_deferred_transaction()?;
         let deleted = tx.delete_key(key)?;
@@ -332,10 +471,67 @@ impl Handle {
             .list_items(prefix)
     }
 
+    /// Lists a single page of items in one transaction.
+    pub fn list_items_page(&self, options: &ListOptions) -> PubResult<Vec<Item>> {
+        self.start_deferred_transaction_for_read()?
+            .list_items_page(options)
+    }
+
+    /// Lists the items with keys in range. See ReadTransaction::list_range.
+    pub fn list_range(
+        &self,
+        range: impl RangeBounds<[u8]>,
+        reverse: bool,
+        limit: Option<usize>,
+    ) -> PubResult<Vec<Item>> {
+        self.start_deferred_transaction_for_read()?
+            .list_range(range, reverse, limit)
+    }
+
+    pub fn prefix_summary(&self, prefix: &[u8]) -> PubResult<PrefixSummary> {
+        self.start_deferred_transaction_for_read()?
+            .prefix_summary(prefix)
+    }
+
+    /// Summarizes keys with prefix by the next key segment up to delimiter, like S3
+    /// CommonPrefixes. See ReadTransaction::prefix_summary_grouped.
+    pub fn prefix_summary_grouped(
+        &self,
+        prefix: &[u8],
+        delimiter: &[u8],
+    ) -> PubResult<Vec<(Vec<u8>, PrefixSummary)>> {
+        self.start_deferred_transaction_for_read()?
+            .prefix_summary_grouped(prefix, delimiter)
+    }
+
+    pub fn count_range(&self, range: impl RangeBounds<[u8]>) -> PubResult<u64> {
+        self.start_deferred_transaction_for_read()?
+            .count_range(range)
+    }
+
+    /// Iterates over items without materializing them all, or holding a transaction for the
+    /// duration.
+    pub fn iter_items(&self, options: ListOptions) -> ItemIter<'_> {
+        ItemIter::new(self, options)
+    }
+
+    /// Returns committed writes, renames, deletes and evictions after seq. Pass 0 to get all the
+    /// changes that are retained.
+    pub fn changes_since(&self, seq: ChangeSeq) -> PubResult<Vec<Change>> {
+        self.start_deferred_transaction_for_read()?
+            .changes_since(seq)
+    }
+
+    /// Subscribes to changes committed for keys starting with prefix, by this or any other
+    /// process using the directory.
+    pub fn watch(&self, prefix: &[u8]) -> PubResult<Watch> {
+        Watch::new(self.dir.path(), prefix.to_vec())
+    }
+
     /// Punches values in batches with its own dedicated connection and read-only transactions.
     fn value_puncher(
         dir: Dir,
-        values_receiver: std::sync::mpsc::Receiver<Vec<NonzeroValueLocation>>,
+        values_receiver: std::sync::mpsc::Receiver<PendingPunches>,
     ) -> Result<()> {
         let manifest_path = dir.path().join(MANIFEST_DB_FILE_NAME);
         use rusqlite::OpenFlags;
@@ -385,14 +581,19 @@ impl Handle {
     }
 
     /// Starts a read transaction to determine punch boundaries. Since punching is never expanded to
-    /// offsets above the targeted values, ongoing writes should not be affected.
+    /// offsets above the targeted values, ongoing writes should not be affected. Returns the values
+    /// that couldn't be punched yet.
     pub(crate) fn punch_values(
         dir: &Dir,
-        values: Vec<NonzeroValueLocation>,
+        values: PendingPunches,
         transaction: &ReadTransactionOwned,
-    ) -> PubResult<Vec<NonzeroValueLocation>> {
+    ) -> PubResult<PendingPunches> {
+        let Some(_clone_lock) = lock_clone_lock_for_punch(dir.path())? else {
+            debug!("can't punch, reads in progress");
+            return Ok(values);
+        };
         let mut failed = Vec::with_capacity(values.len());
-        for v in values {
+        for (v, constraints) in values {
             let NonzeroValueLocation {
                 file_id,
                 file_offset,
@@ -412,17 +613,26 @@ impl Handle {
                 length: *value_length,
                 tx: transaction,
                 block_size: dir.block_size(),
-                constraints: Default::default(),
+                constraints,
             })
             .context(msg)?
             {
-                failed.push(v);
+                failed.push((v, constraints));
             }
         }
         Ok(failed)
     }
 
     pub(crate) fn send_values_for_delete(&self, values: Vec<NonzeroValueLocation>) {
+        self.send_for_punch(
+            values
+                .into_iter()
+                .map(|value| (value, Default::default()))
+                .collect(),
+        )
+    }
+
+    fn send_for_punch(&self, values: PendingPunches) {
         use std::sync::mpsc::TrySendError::*;
         let sender = self.deleted_values.as_ref().unwrap();
         match sender.try_send(values) {
@@ -442,30 +652,281 @@ impl Handle {
         ValuePuncherDone(Arc::clone(&self.value_puncher_done.0))
     }
 
-    pub fn move_prefix(&self, from: &[u8], to: &[u8]) -> Result<()> {
-        let mut tx = self.start_deferred_transaction()?;
-        let items = tx.list_items(from)?;
-        let mut to_vec = to.to_vec();
-        for item in items {
-            to_vec.truncate(to.len());
-            to_vec.extend_from_slice(item.key.strip_prefix(from).unwrap());
-            tx.rename_item(&item.key, &to_vec)?;
+    /// Queues the file regions of evicted extents for the value puncher, which retries them until
+    /// they're punched.
+    pub(crate) fn punch_extents(&self, extents: Vec<NonzeroValueLocation>) {
+        if extents.is_empty() {
+            return;
         }
-        tx.commit(())?.complete();
+        self.send_for_punch(
+            extents
+                .into_iter()
+                .map(|extent| (extent, PunchValueConstraints::extent()))
+                .collect(),
+        )
+    }
+
+    /// Commits a sparse value of the given length for key, with every range missing. Ranges are
+    /// then filled in with write_range.
+    pub fn create_sparse(&self, key: Vec<u8>, length: u64) -> PubResult<()> {
+        let mut writer = self.new_writer()?;
+        writer.stage_sparse(key, length)?;
+        writer.commit()?;
         Ok(())
     }
 
-    pub fn delete_prefix(&self, prefix: &[u8]) -> PubResult<()> {
-        let mut tx = self.start_deferred_transaction()?;
-        for item in tx.list_items(prefix)? {
-            tx.delete_key(&item.key)?;
+    /// Writes data into a sparse value at offset, and marks the range present. The bytes go
+    /// directly into the space reserved for the value, so writes to different ranges can occur
+    /// concurrently.
+    pub fn write_range(&self, key: &[u8], offset: u64, data: &[u8]) -> PubResult<()> {
+        let value = self
+            .start_deferred_transaction_for_read()?
+            .key_value(key)?
+            .ok_or(Error::NoSuchKey)?;
+        let out_of_range =
+            || -> Error { io::Error::new(InvalidInput, "range exceeds value length").into() };
+        let end = offset
+            .checked_add(data.len() as u64)
+            .ok_or_else(out_of_range)?;
+        if end > value.length() {
+            return Err(out_of_range());
+        }
+        let Nonzero(location) = value.location else {
+            return Ok(());
+        };
+        if data.is_empty() {
+            return Ok(());
+        }
+        let mut file = open_file_id(
+            OpenOptions::new().write(true),
+            self.dir.path(),
+            &location.file_id,
+        )?;
+        // This stops the value being punched or its file truncated while we write.
+        let file_offset = location.file_offset + offset;
+        if !file.lock_segment(LockExclusiveNonblock, Some(data.len() as u64), file_offset)? {
+            return Err(io::Error::new(ErrorKind::WouldBlock, "value range is locked").into());
         }
+        let check_generation = |actual: Option<Generation>| {
+            if actual == Some(value.generation()) {
+                Ok(())
+            } else {
+                Err(Error::WriteConflict {
+                    key: key.to_vec(),
+                    expected: Some(value.generation()),
+                    actual,
+                })
+            }
+        };
+        check_generation(
+            self.start_deferred_transaction_for_read()?
+                .key_generation(key)?,
+        )?;
+        positioned_io::WriteAt::write_all_at(&mut file, file_offset, data)?;
+        let mut tx = self.start_immediate_transaction()?;
+        check_generation(tx.key_generation(key)?)?;
+        tx.fill_missing_range(&location, offset, data.len() as u64)?;
         tx.commit(())?.complete();
         Ok(())
     }
-}
 
-use item::Item;
+    /// Writes a copy of the cache as it is at this moment to dest_dir, which must not exist or be
+    /// empty. The manifest is copied with the SQLite online backup API, and every values file the
+    /// copy references is cloned, or copied with its holes intact where cloning isn't supported.
+    /// Commits carry on meanwhile, but punching is held off until the files are copied so the
+    /// values they contain stay intact. The result can be opened with Handle::new like any other
+    /// cache directory.
+    pub fn backup_to(&self, dest_dir: impl AsRef<Path>) -> PubResult<()> {
+        let dest_dir = dest_dir.as_ref();
+        fs::create_dir_all(dest_dir)?;
+        if read_dir(dest_dir)?.next().is_some() {
+            return Err(anyhow!("backup destination {dest_dir:?} is not empty").into());
+        }
+        // Held from before the manifest is read, like a Reader, so nothing the copy references can
+        // be punched until it's been cloned.
+        let _clone_lock = lock_clone_lock_for_read(self.dir.path())?;
+        // A separate connection, so this Handle isn't tied up for the duration.
+        let src_conn = Connection::open(self.dir.path().join(MANIFEST_DB_FILE_NAME))?;
+        let mut dest_conn = Connection::open(dest_dir.join(MANIFEST_DB_FILE_NAME))?;
+        let backup = rusqlite::backup::Backup::new(&src_conn, &mut dest_conn)?;
+        // Copying every page in one step reads the manifest in a single transaction.
+        loop {
+            use rusqlite::backup::StepResult::*;
+            match backup.step(-1).context("backing up manifest")? {
+                Done => break,
+                Busy | Locked => std::thread::sleep(Duration::from_millis(1)),
+                other => return Err(anyhow!("backing up manifest: {other:?}").into()),
+            }
+        }
+        drop(backup);
+        drop(src_conn);
+        let file_ids =
+            ReadTransactionOwned(dest_conn.unchecked_transaction()?).referenced_file_ids()?;
+        drop(dest_conn);
+        for file_id in file_ids {
+            let src_path = file_path(self.dir.path(), file_id);
+            clone_or_copy_file(&src_path, &file_path(dest_dir, file_id))
+                .with_context(|| format!("backing up {src_path:?}"))?;
+        }
+        Ok(())
+    }
+
+    /// Writes the items with keys starting with prefix to writer as a portable archive. All the
+    /// items come from a single read transaction, which is held until the archive is written, so
+    /// other operations on this Handle wait for it. Missing ranges of sparse values are recorded
+    /// rather than written out, and last_used isn't updated.
+    pub fn export(&self, writer: impl Write, prefix: &[u8]) -> PubResult<BulkOpStats> {
+        archive::export(self, writer, prefix)
+    }
+
+    /// Writes the items in an archive from Handle::export, replacing any existing values for
+    /// their keys, and keeping their last_used and missing ranges. Items are committed in batches,
+    /// so an archive that turns out to be invalid part way through can leave some of its items
+    /// restored.
+    pub fn restore(&self, reader: impl Read) -> PubResult<BulkOpStats> {
+        archive::restore(self, reader)
+    }
+
+    /// Imports every regular file under root, keyed by key_prefix followed by its path relative to
+    /// root with '/' separators. Files are cloned in where possible and committed in batches, or
+    /// moved and committed one at a time if options.rename is set, in which case a file whose
+    /// commit fails is moved back. Returns the number of files and bytes imported.
+    pub fn import_dir(
+        &self,
+        root: impl AsRef<Path>,
+        key_prefix: &[u8],
+        mut options: ImportOptions,
+    ) -> PubResult<BulkOpStats> {
+        let root = root.as_ref();
+        // A moved file is gone from root as soon as it's staged, so it would be lost if something
+        // later in its batch failed. Each is committed on its own instead.
+        let batch_size = if options.rename {
+            1
+        } else {
+            options
+                .batch_size
+                .unwrap_or(DEFAULT_IMPORT_BATCH_SIZE)
+                .max(1)
+        };
+        let files = import::walk_files(root)?;
+        let mut stats = BulkOpStats::default();
+        for batch in files.chunks(batch_size) {
+            let mut writer = self.new_writer()?;
+            let mut batch_stats = BulkOpStats::default();
+            if options.rename {
+                let [path] = batch else {
+                    unreachable!("rename imports are committed one file at a time")
+                };
+                let key = import::import_key(key_prefix, root, path)?;
+                let (mut value, how) = writer
+                    .new_value()
+                    .move_or_copy_file(path)
+                    .with_context(|| format!("importing {path:?}"))?;
+                batch_stats.count += 1;
+                batch_stats.value_length_sum += value.value_length()?;
+                match how {
+                    FileImport::Copied => {
+                        writer.stage_write(key, value)?;
+                        writer.commit()?;
+                        // The original is only removed once its copy is committed.
+                        fs::remove_file(path)?;
+                    }
+                    FileImport::Moved => {
+                        let file_id = value.exclusive_file.id;
+                        let values_path = value.exclusive_file.path.clone();
+                        // Kept out of the batch, so it can't be handed to another writer if the
+                        // commit fails and it's moved back.
+                        let mut moved = None;
+                        let res = writer
+                            .stage_write(key, value)
+                            .map_err(Error::from)
+                            .and_then(|()| {
+                                moved = writer.take_exclusive_file(&file_id);
+                                writer.commit().map(drop)
+                            });
+                        if let Err(err) = res {
+                            // Put the file back rather than leave it orphaned in the values
+                            // directory.
+                            if let Err(err) = rename_no_replace(&values_path, path) {
+                                error!(
+                                    "error moving {:?} back to {:?}: {:#?}",
+                                    values_path, path, err
+                                );
+                            }
+                            return Err(err);
+                        }
+                        drop(moved);
+                    }
+                }
+            } else {
+                for path in batch {
+                    let key = import::import_key(key_prefix, root, path)?;
+                    let mut value = File::open(path)
+                        .map_err(Into::into)
+                        .and_then(|mut file| writer.new_value().clone_file(&mut file))
+                        .with_context(|| format!("importing {path:?}"))?;
+                    batch_stats.count += 1;
+                    batch_stats.value_length_sum += value.value_length()?;
+                    writer.stage_write(key, value)?;
+                }
+                writer.commit()?;
+            }
+            stats.count += batch_stats.count;
+            stats.value_length_sum += batch_stats.value_length_sum;
+            if let Some(progress) = &mut options.progress {
+                progress(&stats);
+            }
+        }
+        Ok(stats)
+    }
+
+    /// Makes new_key reference the same stored value as existing_key without copying it. The value
+    /// is only punched once all its keys are removed.
+    pub fn link(&self, existing_key: &[u8], new_key: &[u8]) -> PubResult<Value> {
+        let mut tx = self.start_immediate_transaction()?;
+        let value = tx.link_key(existing_key, new_key)?;
+        Ok(tx.commit(value)?.complete())
+    }
+
+    /// Replaces the prefix from with to on all keys that have it. Fails if any of the new keys
+    /// already exist.
+    pub fn move_prefix(&self, from: &[u8], to: &[u8]) -> PubResult<BulkOpStats> {
+        let mut tx = self.start_immediate_transaction()?;
+        let stats = tx.rename_prefix(from, to)?;
+        Ok(tx.commit(stats)?.complete())
+    }
+
+    /// Deletes all keys with prefix in one transaction.
+    pub fn delete_prefix(&self, prefix: &[u8]) -> PubResult<BulkOpStats> {
+        let mut tx = self.start_immediate_transaction()?;
+        let stats = tx.delete_prefix(prefix, None)?;
+        Ok(tx.commit(stats)?.complete())
+    }
+
+    /// Deletes all keys with prefix, committing every batch_size keys so other writers aren't
+    /// locked out for the whole deletion. Keys written under the prefix concurrently may be deleted
+    /// too. batch_size can't be zero.
+    pub fn delete_prefix_batched(
+        &self,
+        prefix: &[u8],
+        batch_size: usize,
+    ) -> PubResult<BulkOpStats> {
+        if batch_size == 0 {
+            return Err(io::Error::new(InvalidInput, "batch size is zero").into());
+        }
+        let mut total = BulkOpStats::default();
+        loop {
+            let mut tx = self.start_immediate_transaction()?;
+            let stats = tx.delete_prefix(prefix, Some(batch_size))?;
+            tx.commit(())?.complete();
+            total.count += stats.count;
+            total.value_length_sum += stats.value_length_sum;
+            if stats.count < batch_size as u64 {
+                return Ok(total);
+            }
+        }
+    }
+}
 
 use crate::dir::Dir;
 use crate::ownedtx::{OwnedReadTx, OwnedTxInner};
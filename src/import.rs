@@ -0,0 +1,66 @@
+//! Seeding a cache from files on disk.
+
+use super::*;
+
+/// The number of files Handle::import_dir commits at a time by default.
+pub const DEFAULT_IMPORT_BATCH_SIZE: usize = 256;
+
+/// Receives the running totals of an import.
+pub type ImportProgress = Box<dyn FnMut(&BulkOpStats)>;
+
+/// Controls how Handle::import_dir ingests files.
+#[derive(Default)]
+pub struct ImportOptions {
+    /// Move files into the cache instead of cloning or copying them. They must be on the same
+    /// filesystem as the cache.
+    pub rename: bool,
+    /// The number of files to commit at a time. Defaults to DEFAULT_IMPORT_BATCH_SIZE. Moved files
+    /// are always committed one at a time.
+    pub batch_size: Option<usize>,
+    /// Called after each commit with the totals imported so far.
+    pub progress: Option<ImportProgress>,
+}
+
+impl Debug for ImportOptions {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("ImportOptions")
+            .field("rename", &self.rename)
+            .field("batch_size", &self.batch_size)
+            .finish_non_exhaustive()
+    }
+}
+
+/// Returns the regular files under root, in path order. Symlinks are not followed.
+pub(crate) fn walk_files(root: &Path) -> io::Result<Vec<PathBuf>> {
+    let mut files = vec![];
+    let mut dirs = vec![root.to_owned()];
+    while let Some(dir) = dirs.pop() {
+        for entry in read_dir(&dir)? {
+            let entry = entry?;
+            let file_type = entry.file_type()?;
+            if file_type.is_dir() {
+                dirs.push(entry.path());
+            } else if file_type.is_file() {
+                files.push(entry.path());
+            } else {
+                debug!(path = ?entry.path(), "skipping import of non-regular file");
+            }
+        }
+    }
+    files.sort();
+    Ok(files)
+}
+
+/// The key for a file imported from root: key_prefix followed by the relative path, with
+/// components separated by '/' on every platform.
+pub(crate) fn import_key(key_prefix: &[u8], root: &Path, path: &Path) -> Result<Vec<u8>> {
+    let relative = path.strip_prefix(root)?;
+    let mut key = key_prefix.to_vec();
+    for (index, component) in relative.components().enumerate() {
+        if index != 0 {
+            key.push(b'/');
+        }
+        key.extend_from_slice(component.as_os_str().as_encoded_bytes());
+    }
+    Ok(key)
+}
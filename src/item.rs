@@ -1,6 +1,134 @@
-use crate::Value;
+use crate::{Handle, PubResult, Timestamp, Value, VALUE_COLUMN_NAMES};
 
 pub struct Item {
     pub key: Vec<u8>,
     pub value: Value,
 }
+
+impl Item {
+    /// Reads an Item from a row of the value columns followed by the key.
+    pub(crate) fn from_row(row: &rusqlite::Row) -> rusqlite::Result<Self> {
+        Ok(Self {
+            value: Value::from_row(row)?,
+            key: row.get(VALUE_COLUMN_NAMES.len())?,
+        })
+    }
+}
+
+/// Aggregate stats for a set of keys.
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct PrefixSummary {
+    pub count: u64,
+    pub value_length_sum: u64,
+    /// The least recent last_used, or None if there are no keys.
+    pub oldest_last_used: Option<Timestamp>,
+    pub newest_last_used: Option<Timestamp>,
+}
+
+pub(crate) const PREFIX_SUMMARY_COLUMNS_SQL: &str =
+    "count(*), coalesce(sum(value_length), 0), min(last_used), max(last_used)";
+
+impl PrefixSummary {
+    /// Reads a summary from PREFIX_SUMMARY_COLUMNS_SQL starting at column offset.
+    pub(crate) fn from_row(row: &rusqlite::Row, offset: usize) -> rusqlite::Result<Self> {
+        Ok(Self {
+            count: row.get(offset)?,
+            value_length_sum: row.get(offset + 1)?,
+            oldest_last_used: row.get(offset + 2)?,
+            newest_last_used: row.get(offset + 3)?,
+        })
+    }
+}
+
+/// The number of keys and value bytes affected by a bulk operation.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct BulkOpStats {
+    pub count: u64,
+    pub value_length_sum: u64,
+}
+
+impl BulkOpStats {
+    pub(crate) fn add(&mut self, value: &Value) {
+        self.count += 1;
+        self.value_length_sum += value.length();
+    }
+}
+
+/// Selects a page of items in key order.
+#[derive(Debug, Clone, Default)]
+pub struct ListOptions {
+    /// Only keys with this prefix are listed.
+    pub prefix: Vec<u8>,
+    /// Listing resumes after this key, which is usually the last key of the previous page.
+    pub start_after: Option<Vec<u8>>,
+    /// The maximum number of items to return.
+    pub limit: Option<usize>,
+    /// List in descending key order.
+    pub reverse: bool,
+}
+
+const DEFAULT_LIST_PAGE_SIZE: usize = 1000;
+
+/// Iterates over items a page at a time, reading each page in its own transaction so the manifest
+/// isn't held for the whole listing. Changes committed between pages may or may not be seen.
+pub struct ItemIter<'h> {
+    handle: &'h Handle,
+    options: ListOptions,
+    page_size: usize,
+    page: std::vec::IntoIter<Item>,
+    done: bool,
+}
+
+impl<'h> ItemIter<'h> {
+    pub(crate) fn new(handle: &'h Handle, options: ListOptions) -> Self {
+        Self {
+            handle,
+            options,
+            page_size: DEFAULT_LIST_PAGE_SIZE,
+            page: Default::default(),
+            done: false,
+        }
+    }
+
+    /// Sets the number of items read in each transaction. A page size of 0 is treated as 1.
+    pub fn with_page_size(mut self, page_size: usize) -> Self {
+        self.page_size = page_size.max(1);
+        self
+    }
+}
+
+impl Iterator for ItemIter<'_> {
+    type Item = PubResult<Item>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            if let Some(item) = self.page.next() {
+                self.options.start_after = Some(item.key.clone());
+                if let Some(limit) = &mut self.options.limit {
+                    *limit -= 1;
+                }
+                return Some(Ok(item));
+            }
+            let page_limit =
+                std::cmp::min(self.options.limit.unwrap_or(usize::MAX), self.page_size);
+            if self.done || page_limit == 0 {
+                return None;
+            }
+            let page_options = ListOptions {
+                limit: Some(page_limit),
+                ..self.options.clone()
+            };
+            match self.handle.list_items_page(&page_options) {
+                Err(err) => {
+                    self.done = true;
+                    return Some(Err(err));
+                }
+                Ok(items) => {
+                    // A short page means there's nothing more to get.
+                    self.done = items.len() < page_limit;
+                    self.page = items.into_iter();
+                }
+            }
+        }
+    }
+}
@@ -1,7 +1,7 @@
 #![allow(clippy::unused_unit)]
 
 use std::borrow::Borrow;
-use std::cmp::min;
+use std::cmp::{max, min};
 use std::collections::{BTreeSet, HashMap, HashSet};
 use std::ffi::{OsStr, OsString};
 use std::fmt::{Debug, Display, Formatter};
@@ -9,7 +9,7 @@ use std::fs::{read_dir, remove_dir, remove_file, File, OpenOptions};
 use std::io::SeekFrom::{End, Start};
 use std::io::{ErrorKind, Read, Seek, Write};
 use std::num::TryFromIntError;
-use std::ops::{Deref, DerefMut};
+use std::ops::{Deref, DerefMut, Range};
 use std::path::{Path, PathBuf};
 use std::sync::{Arc, Mutex, MutexGuard, OnceLock};
 use std::time::Duration;
@@ -22,8 +22,8 @@ use env::flocking;
 pub use error::*;
 use exclusive_file::ExclusiveFile;
 use file_id::FileId;
-pub use handle::Handle;
-use memmap2::Mmap;
+pub use handle::{Handle, Limits};
+use memmap2::{Mmap, MmapMut};
 use num::Integer;
 use ownedtx::OwnedTx;
 use positioned_io::ReadAt;
@@ -38,18 +38,26 @@ pub use test_log::test;
 use tracing::*;
 use ErrorKind::InvalidInput;
 
-use crate::item::Item;
+pub use crate::item::{BulkOpStats, Item, ItemIter, ListOptions, PrefixSummary};
 use crate::walk::walk_dir;
 use crate::ValueLocation::{Nonzero, ZeroLength};
 
+mod archive;
+pub use archive::{ARCHIVE_MAGIC, ARCHIVE_VERSION};
 mod c_api;
+mod changes;
+pub use changes::*;
 mod cpathbuf;
 mod dir;
 mod error;
 mod exclusive_file;
 mod file_id;
 pub(crate) mod handle;
+mod import;
+pub use import::{ImportOptions, ImportProgress, DEFAULT_IMPORT_BATCH_SIZE};
 mod item;
+mod named_snapshot;
+pub use named_snapshot::NamedSnapshot;
 mod owned_cell;
 pub mod sys;
 #[cfg(feature = "testing")]
@@ -64,6 +72,8 @@ pub use dir::*;
 pub mod env;
 mod reader;
 use reader::Reader;
+mod watch;
+pub use watch::Watch;
 
 /// Type to be exposed eventually from the lib instead of anyhow. Should be useful for the C API.
 pub type PubResult<T> = Result<T, Error>;
@@ -102,6 +112,10 @@ struct PendingWrite {
     value_file_offset: u64,
     value_length: u64,
     value_file_id: FileId,
+    // Ranges of the value that are reserved but not yet written.
+    missing_ranges: Vec<Range<u64>>,
+    // Overrides the commit time as the value's last_used.
+    last_used: Option<Timestamp>,
 }
 
 const MANIFEST_SCHEMA_SQL: &str = include_str!("../manifest.sql");
@@ -117,17 +131,37 @@ pub struct BeginWriteValue<'writer, 'handle> {
 }
 
 impl BeginWriteValue<'_, '_> {
-    // TODO: On Linux and Windows, this should be possible without creating a new file. I'm not sure
-    // if it's worth it however, since cloned blocks have to be a of a minimum size and alignment.
-    // See also
+    // See
     // https://stackoverflow.com/questions/65505765/difference-of-ficlone-vs-ficlonerange-vs-copy-file-range-for-copy-on-write-supp
     // for a discussion on efficient ways to copy values that could be supported.
-    /// Clone an entire file in. If cloning fails, this will fall back to copying the provided file.
-    /// Its file position may be altered.
+    /// Clone an entire file in. The file is cloned into the end of an exclusive file where range
+    /// cloning is supported, otherwise into a new values file. If cloning fails, this will fall
+    /// back to copying the provided file. Its file position may be altered.
     pub fn clone_file(self, file: &mut File) -> PubResult<ValueWriter> {
         if !self.batch.handle.dir_supports_file_cloning() {
             return self.copy_file(file);
         }
+        let mut exclusive_file = self.batch.get_exclusive_file()?;
+        let block_size = self.batch.handle.block_size();
+        match exclusive_file.clone_range_in(file, block_size) {
+            Ok(value_file_offset) => {
+                return Ok(ValueWriter {
+                    reservation: None,
+                    mapped: None,
+                    exclusive_file,
+                    value_file_offset,
+                    revert_offset: value_file_offset,
+                })
+            }
+            Err(err) => {
+                self.batch.exclusive_files.push(exclusive_file);
+                if err.is_unsupported() {
+                    return self.copy_file(file);
+                }
+                // Range cloning can have stricter alignment requirements than cloning whole files.
+                debug!(?err, "cloning range into exclusive file");
+            }
+        }
         let dst_path = loop {
             let dst_path = self
                 .batch
@@ -149,35 +183,224 @@ impl BeginWriteValue<'_, '_> {
         // we cloned and move on.
         let exclusive_file = ExclusiveFile::open(dst_path)?.unwrap();
         Ok(ValueWriter {
+            reservation: None,
+            mapped: None,
             exclusive_file,
             value_file_offset: 0,
+            revert_offset: 0,
         })
     }
 
-    /// Assigns an exclusive file for writing, and copies the entire source file.
+    /// Moves the file at path into the values directory as a new values file holding the value,
+    /// without copying it. path mustn't have other hard links, since punching the value later
+    /// would change them too. If path is on another filesystem, it's copied in and then removed.
+    /// The file is gone from path once this succeeds, whether or not the value is committed.
+    pub fn rename_file(self, path: impl AsRef<Path>) -> PubResult<ValueWriter> {
+        let src_path = path.as_ref();
+        let (value, how) = self.move_or_copy_file(src_path)?;
+        if let FileImport::Copied = how {
+            remove_file(src_path)?;
+        }
+        Ok(value)
+    }
+
+    /// Like rename_file, but a file on another filesystem is left in place after it's copied.
+    pub(crate) fn move_or_copy_file(self, src_path: &Path) -> PubResult<(ValueWriter, FileImport)> {
+        let dir = self.batch.handle.dir.path();
+        let mut dst_path = dir.join(FileId::random().values_file_path());
+        // Locked before it's moved into the values directory, where other writers look for values
+        // files to append to.
+        let mut exclusive_file = ExclusiveFile::open_uncommitted(src_path, dst_path.clone())?
+            .context("file to rename is locked")?;
+        if file_link_count(&exclusive_file.inner)? > 1 {
+            return Err(io::Error::new(InvalidInput, "file to rename has other hard links").into());
+        }
+        loop {
+            match rename_no_replace(src_path, &dst_path) {
+                Err(err) if err.is_file_already_exists() => {
+                    dst_path = dir.join(FileId::random().values_file_path());
+                }
+                Err(err) if err.kind() == ErrorKind::CrossesDevices => {
+                    drop(exclusive_file);
+                    let value = self.copy_file(&mut File::open(src_path)?)?;
+                    return Ok((value, FileImport::Copied));
+                }
+                Err(err) => return Err(err.into()),
+                Ok(()) => break,
+            }
+        }
+        exclusive_file.id = dst_path.file_name().context("file name")?.try_into()?;
+        exclusive_file.path = dst_path;
+        // The file's contents weren't written by us, so a failed stage mustn't truncate them.
+        let revert_offset = exclusive_file.next_write_offset()?;
+        let value = ValueWriter {
+            reservation: None,
+            mapped: None,
+            exclusive_file,
+            value_file_offset: 0,
+            revert_offset,
+        };
+        Ok((value, FileImport::Moved))
+    }
+
+    /// Assigns an exclusive file for writing, and copies the entire source file. Only its data
+    /// regions are copied, so holes in the source stay holes in the value.
     fn copy_file(self, file: &mut File) -> PubResult<ValueWriter> {
-        let mut value_writer = self.begin()?;
-        // Need to rewind the file since we're cloning the whole thing.
-        file.seek(Start(0))?;
-        value_writer.copy_from(file)?;
-        Ok(value_writer)
+        let length = file.metadata()?.len();
+        let data = data_ranges(file, 0..length)?;
+        let block_size = self.batch.handle.block_size();
+        let mut exclusive_file = self.batch.get_exclusive_file()?;
+        let start = exclusive_file.next_write_offset()?;
+        let res = (|| {
+            let mut value_file_offset = start;
+            if data
+                .iter()
+                .map(|range| range.end - range.start)
+                .sum::<u64>()
+                != length
+            {
+                // Aligning the value lets holes in the source line up with whole blocks.
+                value_file_offset = ceil_multiple(start, block_size);
+            }
+            exclusive_file.extend(value_file_offset - start + length)?;
+            // copy_file_range refuses destinations opened for appending.
+            let mut dst = OpenOptions::new().write(true).open(&exclusive_file.path)?;
+            for range in data {
+                let range_length = range.end - range.start;
+                let copied = copy_range(
+                    file,
+                    range.start,
+                    &mut dst,
+                    value_file_offset + range.start,
+                    range_length,
+                )?;
+                if copied != range_length {
+                    return Err(io::Error::new(
+                        ErrorKind::UnexpectedEof,
+                        "file shrank while copying",
+                    ));
+                }
+            }
+            Ok(value_file_offset)
+        })();
+        match res {
+            Ok(value_file_offset) => Ok(ValueWriter {
+                reservation: None,
+                mapped: None,
+                exclusive_file,
+                value_file_offset,
+                revert_offset: start,
+            }),
+            Err(err) => {
+                if let Err(err) = exclusive_file.revert_to_offset(start) {
+                    error!("error reverting file copy: {:#?}", err);
+                } else {
+                    self.batch.exclusive_files.push(exclusive_file);
+                }
+                Err(err.into())
+            }
+        }
     }
 
     /// Assign an exclusive file for writing a value.
     pub fn begin(self) -> PubResult<ValueWriter> {
         let mut exclusive_file = self.batch.get_exclusive_file()?;
+        let value_file_offset = exclusive_file.next_write_offset()?;
         Ok(ValueWriter {
-            value_file_offset: exclusive_file.next_write_offset()?,
+            reservation: None,
+            mapped: None,
             exclusive_file,
+            value_file_offset,
+            revert_offset: value_file_offset,
         })
     }
+
+    /// Assign an exclusive file for writing a value of exactly length bytes. The space is
+    /// preallocated so that running out fails here rather than partway through writing. Staging
+    /// the value fails if a different number of bytes was written, and the reserved space is
+    /// released if the ValueWriter is dropped without being staged.
+    pub fn begin_with_size(self, length: u64) -> PubResult<ValueWriter> {
+        let mut exclusive_file = self.batch.get_exclusive_file()?;
+        let value_file_offset = exclusive_file.next_write_offset()?;
+        let reservation = exclusive_file.inner.try_clone().and_then(|file| {
+            exclusive_file.preallocate(length)?;
+            Ok(Reservation {
+                file: Some(file),
+                value_file_offset,
+                length,
+                positional_file: None,
+                written: vec![],
+            })
+        });
+        let reservation = match reservation {
+            Ok(ok) => ok,
+            Err(err) => {
+                self.batch.exclusive_files.push(exclusive_file);
+                return Err(err.into());
+            }
+        };
+        Ok(ValueWriter {
+            reservation: Some(reservation),
+            mapped: None,
+            exclusive_file,
+            value_file_offset,
+            revert_offset: value_file_offset,
+        })
+    }
+}
+
+/// How ValueWriter::move_or_copy_file brought a file into the cache.
+pub(crate) enum FileImport {
+    /// The file is now the values file of the ValueWriter.
+    Moved,
+    /// The file was copied, and is still at its original path.
+    Copied,
+}
+
+/// Space preallocated in an exclusive file for a value of known length.
+#[derive(Debug)]
+struct Reservation {
+    // Shares the exclusive file's locks. Taken when the reservation is used by a staged write.
+    file: Option<File>,
+    value_file_offset: u64,
+    length: u64,
+    // A non-append file for ValueWriter::write_at, opened on first use.
+    positional_file: Option<File>,
+    // Sorted, merged value ranges written positionally, including anything appended before the
+    // first positional write.
+    written: Vec<Range<u64>>,
+}
+
+impl Reservation {
+    fn missing_ranges(&self) -> Vec<Range<u64>> {
+        subtract_ranges(0..self.length, &self.written)
+    }
+}
+
+impl Drop for Reservation {
+    fn drop(&mut self) {
+        let Some(file) = self.file.take() else {
+            return;
+        };
+        // Truncating discards anything written for the value and frees the blocks allocated past
+        // the end of the file.
+        if let Err(err) = file.set_len(self.value_file_offset) {
+            error!("error releasing abandoned value reservation: {:#?}", err);
+        }
+    }
 }
 
-// TODO: Implement Drop for ValueWriter?
 #[derive(Debug)]
 pub struct ValueWriter {
+    // Declared first so it's dropped while the exclusive file is still locked.
+    reservation: Option<Reservation>,
+    // The latest region returned by map_mut.
+    mapped: Option<MmapMut>,
     exclusive_file: ExclusiveFile,
     value_file_offset: u64,
+    // Where the file ended before anything was written for the value, and where it's truncated to
+    // if staging fails. It's after value_file_offset when appending to a committed value.
+    revert_offset: u64,
 }
 
 impl ValueWriter {
@@ -200,9 +423,147 @@ impl ValueWriter {
         Ok(value_length)
     }
 
+    /// Appends length bytes of file from offset, with copy_file_range where it's supported so the
+    /// data doesn't pass through user space. Returns the number of bytes copied, which is less
+    /// than length if file ends first.
+    pub fn copy_from_file(&mut self, file: &File, offset: u64, length: u64) -> PubResult<u64> {
+        let value_file_offset = self.exclusive_file.next_write_offset()?;
+        match self.copy_from_file_inner(file, offset, length, value_file_offset) {
+            Ok(copied) => Ok(copied),
+            Err(err) => {
+                if let Err(err) = self.exclusive_file.revert_to_offset(value_file_offset) {
+                    error!("error reverting failed file copy: {:#?}", err);
+                }
+                Err(err.into())
+            }
+        }
+    }
+
+    fn copy_from_file_inner(
+        &mut self,
+        file: &File,
+        offset: u64,
+        length: u64,
+        dst_offset: u64,
+    ) -> io::Result<u64> {
+        if length == 0 {
+            return Ok(0);
+        }
+        // copy_file_range refuses destinations opened for appending.
+        let mut dst = OpenOptions::new()
+            .write(true)
+            .open(&self.exclusive_file.path)?;
+        let copied = copy_range(file, offset, &mut dst, dst_offset, length)?;
+        self.exclusive_file.inner.seek(Start(dst_offset + copied))?;
+        Ok(copied)
+    }
+
     pub fn value_length(&mut self) -> io::Result<u64> {
         Ok(self.exclusive_file.next_write_offset()? - self.value_file_offset)
     }
+
+    /// Writes buf at offset into a value begun with BeginWriteValue::begin_with_size, for values
+    /// that arrive out of order. The first positional write extends the value to its reserved
+    /// length, so anything appended after that makes the value too long to stage. Staging fails
+    /// with Error::IncompleteValue until every byte has been written, unless the value is staged
+    /// with BatchWriter::stage_incomplete.
+    pub fn write_at(&mut self, offset: u64, buf: &[u8]) -> PubResult<()> {
+        let Some(reservation) = &mut self.reservation else {
+            return Err(io::Error::new(
+                InvalidInput,
+                "positional writes require a reserved length",
+            )
+            .into());
+        };
+        let end = offset
+            .checked_add(buf.len() as u64)
+            .filter(|end| *end <= reservation.length)
+            .ok_or_else(|| io::Error::new(InvalidInput, "range exceeds reserved length"))?;
+        let positional_file = match &mut reservation.positional_file {
+            Some(file) => file,
+            None => {
+                let appended = self.exclusive_file.next_write_offset()? - self.value_file_offset;
+                if appended > reservation.length {
+                    return Err(Error::ValueLengthMismatch {
+                        expected: reservation.length,
+                        actual: appended,
+                    });
+                }
+                let file = OpenOptions::new()
+                    .write(true)
+                    .open(&self.exclusive_file.path)?;
+                self.exclusive_file.extend(reservation.length - appended)?;
+                merge_range(&mut reservation.written, 0..appended);
+                reservation.positional_file.insert(file)
+            }
+        };
+        positioned_io::WriteAt::write_all_at(
+            positional_file,
+            self.value_file_offset + offset,
+            buf,
+        )?;
+        merge_range(&mut reservation.written, offset..end);
+        Ok(())
+    }
+
+    /// Extends the value by length bytes and returns them mapped writable, for encoders that fill
+    /// a buffer in place. The mapping is flushed and replaced by the next call, and flushed and
+    /// unmapped when the value is staged.
+    pub fn map_mut(&mut self, length: u64) -> PubResult<&mut [u8]> {
+        if let Some(mapped) = self.mapped.take() {
+            mapped.flush()?;
+        }
+        if length == 0 {
+            return Ok(&mut []);
+        }
+        let offset = self.exclusive_file.next_write_offset()?;
+        // Writing to a hole through a mapping faults with SIGBUS if the disk is full, so the space
+        // is allocated first.
+        let map = self
+            .exclusive_file
+            .preallocate(length)
+            .and_then(|()| self.exclusive_file.extend(length))
+            .and_then(|()| {
+                // The exclusive file is append-only, which can't be mapped writable.
+                let file = OpenOptions::new()
+                    .read(true)
+                    .write(true)
+                    .open(&self.exclusive_file.path)?;
+                let len = length
+                    .try_into()
+                    .map_err(|_| io::Error::new(InvalidInput, "length exceeds address space"))?;
+                unsafe {
+                    memmap2::MmapOptions::new()
+                        .offset(offset)
+                        .len(len)
+                        .map_mut(&file)
+                }
+            });
+        match map {
+            Ok(map) => Ok(self.mapped.insert(map)),
+            Err(err) => {
+                if let Err(err) = self.exclusive_file.revert_to_offset(offset) {
+                    error!("error reverting value mapping: {:#?}", err);
+                }
+                Err(err.into())
+            }
+        }
+    }
+
+    /// The ranges of a reserved value that haven't been written yet.
+    pub fn missing_ranges(&mut self) -> io::Result<Vec<Range<u64>>> {
+        let Some(reservation) = &self.reservation else {
+            return Ok(vec![]);
+        };
+        if reservation.positional_file.is_some() {
+            return Ok(reservation.missing_ranges());
+        }
+        let appended = self.exclusive_file.next_write_offset()? - self.value_file_offset;
+        Ok(subtract_ranges(
+            0..reservation.length,
+            std::slice::from_ref(&(0..appended)),
+        ))
+    }
 }
 
 impl Write for ValueWriter {
@@ -211,6 +572,10 @@ impl Write for ValueWriter {
         file.write(buf)
     }
 
+    fn write_vectored(&mut self, bufs: &[io::IoSlice<'_>]) -> io::Result<usize> {
+        self.exclusive_file.inner.write_vectored(bufs)
+    }
+
     fn flush(&mut self) -> io::Result<()> {
         // This makes no sense until commit.
         Ok(())
@@ -230,6 +595,23 @@ pub struct BatchWriter<'a> {
     exclusive_files: Vec<ExclusiveFile>,
     pending_writes: Vec<PendingWrite>,
     value_renames: Vec<ValueRename>,
+    links: Vec<KeyLink>,
+    preconditions: Vec<KeyPrecondition>,
+    released_fetch_claims: Vec<(Vec<u8>, FetchClaimId)>,
+}
+
+/// A key to be made to reference the value of another key on commit.
+#[derive(Debug)]
+struct KeyLink {
+    existing_key: Vec<u8>,
+    new_key: Vec<u8>,
+}
+
+/// The generation a key must have (or None if it must not exist) for a batch to commit.
+#[derive(Debug)]
+struct KeyPrecondition {
+    key: Vec<u8>,
+    expected: Option<Generation>,
 }
 
 pub type TimestampInner = NaiveDateTime;
@@ -247,6 +629,12 @@ impl FromSql for Timestamp {
     }
 }
 
+impl ToSql for Timestamp {
+    fn to_sql(&self) -> rusqlite::Result<ToSqlOutput<'_>> {
+        Ok(self.0.and_utc().timestamp_millis().into())
+    }
+}
+
 // This may only be public for external tests.
 pub const LAST_USED_RESOLUTION: Duration = Duration::from_millis(1);
 
@@ -258,6 +646,47 @@ impl Deref for Timestamp {
     }
 }
 
+/// Identifies a committed value for a key. Every write to a key gets a new generation, and
+/// generations are never reused, even across deletes.
+#[derive(Debug, PartialEq, Eq, Copy, Clone, PartialOrd, Ord, Hash)]
+pub struct Generation(u64);
+
+impl FromSql for Generation {
+    fn column_result(value: ValueRef<'_>) -> FromSqlResult<Self> {
+        Ok(Self(u64::column_result(value)?))
+    }
+}
+
+impl ToSql for Generation {
+    fn to_sql(&self) -> rusqlite::Result<ToSqlOutput<'_>> {
+        self.0.to_sql()
+    }
+}
+
+impl Deref for Generation {
+    type Target = u64;
+
+    fn deref(&self) -> &Self::Target {
+        &self.0
+    }
+}
+
+/// Identifies a handle's claim to fetch a missing key.
+#[derive(Debug, PartialEq, Eq, Copy, Clone)]
+struct FetchClaimId(i64);
+
+impl FetchClaimId {
+    fn random() -> Self {
+        Self(rand::random())
+    }
+}
+
+impl ToSql for FetchClaimId {
+    fn to_sql(&self) -> rusqlite::Result<ToSqlOutput<'_>> {
+        self.0.to_sql()
+    }
+}
+
 pub struct WriteCommitResult {
     count: usize,
 }
@@ -268,7 +697,13 @@ impl WriteCommitResult {
     }
 }
 
-const VALUE_COLUMN_NAMES: &[&str] = &["file_id", "file_offset", "value_length", "last_used"];
+const VALUE_COLUMN_NAMES: &[&str] = &[
+    "file_id",
+    "file_offset",
+    "value_length",
+    "last_used",
+    "generation",
+];
 
 fn value_columns_sql() -> &'static str {
     static ONCE: OnceLock<String> = OnceLock::new();
@@ -284,14 +719,44 @@ impl<'handle> BatchWriter<'handle> {
         self.handle.get_exclusive_file()
     }
 
-    pub fn stage_write(&mut self, key: Vec<u8>, mut value: ValueWriter) -> anyhow::Result<()> {
-        let value_length = match value.value_length() {
+    pub fn stage_write(&mut self, key: Vec<u8>, value: ValueWriter) -> anyhow::Result<()> {
+        self.stage_write_inner(key, value, false)
+    }
+
+    /// Removes a staged value's exclusive file from the batch, so it isn't reused or returned to
+    /// the Handle by the commit.
+    pub(crate) fn take_exclusive_file(&mut self, file_id: &FileId) -> Option<ExclusiveFile> {
+        let index = self
+            .exclusive_files
+            .iter()
+            .position(|ef| ef.id == *file_id)?;
+        Some(self.exclusive_files.swap_remove(index))
+    }
+
+    /// Stages a value that was only partly written with ValueWriter::write_at. The unwritten
+    /// ranges are committed as missing, like those of a sparse value, and can be filled later with
+    /// Handle::write_range.
+    pub fn stage_incomplete(&mut self, key: Vec<u8>, value: ValueWriter) -> anyhow::Result<()> {
+        self.stage_write_inner(key, value, true)
+    }
+
+    fn stage_write_inner(
+        &mut self,
+        key: Vec<u8>,
+        mut value: ValueWriter,
+        allow_incomplete: bool,
+    ) -> anyhow::Result<()> {
+        // Writes through the mapping are already in the file, but errors writing them back only
+        // show up when it's flushed.
+        let value_length = match value
+            .mapped
+            .take()
+            .map_or(Ok(()), |mapped| mapped.flush())
+            .and_then(|()| value.value_length())
+        {
             Ok(ok) => ok,
             Err(err) => {
-                if let Err(err) = value
-                    .exclusive_file
-                    .revert_to_offset(value.value_file_offset)
-                {
+                if let Err(err) = value.exclusive_file.revert_to_offset(value.revert_offset) {
                     error!("error reverting value write: {:#?}", err);
                 }
                 // The ExclusiveFile is probably broken in some way if we couldn't seek on it. Don't
@@ -299,6 +764,30 @@ impl<'handle> BatchWriter<'handle> {
                 return Err(err.into());
             }
         };
+        let mut missing_ranges = vec![];
+        if let Some(mut reservation) = value.reservation.take() {
+            // The reservation is used, or released by reverting below.
+            reservation.file = None;
+            let err = if value_length != reservation.length {
+                Some(Error::ValueLengthMismatch {
+                    expected: reservation.length,
+                    actual: value_length,
+                })
+            } else if reservation.positional_file.is_some() {
+                missing_ranges = reservation.missing_ranges();
+                (!missing_ranges.is_empty() && !allow_incomplete).then(|| Error::IncompleteValue {
+                    missing: std::mem::take(&mut missing_ranges),
+                })
+            } else {
+                None
+            };
+            if let Some(err) = err {
+                // Truncating also frees the blocks allocated for the rest of the reservation.
+                value.exclusive_file.revert_to_offset(value.revert_offset)?;
+                self.exclusive_files.push(value.exclusive_file);
+                return Err(err.into());
+            }
+        }
         let exclusive_file = value.exclusive_file;
         let value_file_id = exclusive_file.id;
         self.exclusive_files.push(exclusive_file);
@@ -307,10 +796,179 @@ impl<'handle> BatchWriter<'handle> {
             value_file_offset: value.value_file_offset,
             value_length,
             value_file_id,
+            missing_ranges,
+            last_used: None,
         });
         Ok(())
     }
 
+    /// Sets the last_used of the most recently staged write, instead of the time it's committed.
+    pub(crate) fn set_staged_last_used(&mut self, last_used: Timestamp) {
+        self.pending_writes.last_mut().unwrap().last_used = Some(last_used);
+    }
+
+    /// Stages a sparse value for key of the given length. Space is reserved for it as a hole in a
+    /// values file, and all of it is missing until written with Handle::write_range.
+    pub fn stage_sparse(&mut self, key: Vec<u8>, length: u64) -> PubResult<()> {
+        let mut exclusive_file = self.get_exclusive_file()?;
+        let value_file_offset = exclusive_file.next_write_offset()?;
+        if let Err(err) = exclusive_file.extend(length) {
+            if let Err(err) = exclusive_file.revert_to_offset(value_file_offset) {
+                error!("error reverting sparse value reservation: {:#?}", err);
+            }
+            return Err(err.into());
+        }
+        let value_file_id = exclusive_file.id;
+        self.exclusive_files.push(exclusive_file);
+        self.pending_writes.push(PendingWrite {
+            key,
+            value_file_offset,
+            value_length: length,
+            value_file_id,
+            missing_ranges: subtract_ranges(0..length, &[]),
+            last_used: None,
+        });
+        Ok(())
+    }
+
+    /// Stages a write that only commits if the key's current generation is expected, or if the key
+    /// doesn't exist when expected is None. If any precondition in the batch fails, the commit
+    /// returns Error::WriteConflict and nothing in the batch is applied.
+    pub fn stage_write_if(
+        &mut self,
+        key: Vec<u8>,
+        value: ValueWriter,
+        expected: Option<Generation>,
+    ) -> anyhow::Result<()> {
+        self.preconditions.push(KeyPrecondition {
+            key: key.clone(),
+            expected,
+        });
+        self.stage_write(key, value)
+    }
+
+    /// Stages a write that only commits if the key doesn't exist.
+    pub fn put_if_absent(&mut self, key: Vec<u8>, value: ValueWriter) -> anyhow::Result<()> {
+        self.stage_write_if(key, value, None)
+    }
+
+    /// Returns a ValueWriter that continues the value committed for key, so that staging it
+    /// replaces the value with an extended one. If the value is at the end of a values file that
+    /// can be locked exclusively, it's extended in place. Otherwise the existing bytes are copied
+    /// into a new value first, cloning whole blocks where possible. Appending doesn't update the
+    /// value's last_used. The commit fails with Error::WriteConflict if the key changes in the
+    /// meantime.
+    pub fn append_to(&mut self, key: &[u8]) -> PubResult<ValueWriter> {
+        let value = self
+            .handle
+            .start_deferred_transaction_for_read()?
+            .key_value(key)?
+            .ok_or(Error::NoSuchKey)?;
+        if let Nonzero(location) = value.location {
+            if let Some(exclusive_file) = self.exclusive_file_for_append(&location)? {
+                debug!(?location, "appending to value in place");
+                self.preconditions.push(KeyPrecondition {
+                    key: key.to_vec(),
+                    expected: Some(value.generation()),
+                });
+                return Ok(ValueWriter {
+                    reservation: None,
+                    mapped: None,
+                    exclusive_file,
+                    value_file_offset: location.file_offset,
+                    revert_offset: location.file_offset + location.length,
+                });
+            }
+        }
+        // Appending is a write, so reading the existing value shouldn't update last_used.
+        let mut reader = self.handle.read()?;
+        let value = reader.add(key)?.ok_or(Error::NoSuchKey)?;
+        let snapshot_value = reader.snapshot_without_touching()?.value(value);
+        drop(reader);
+        self.preconditions.push(KeyPrecondition {
+            key: key.to_vec(),
+            expected: Some(snapshot_value.generation()),
+        });
+        self.clone_or_copy_value(&snapshot_value)
+    }
+
+    /// Begins a new value holding the bytes of value. If value is block-aligned in its values file,
+    /// its whole blocks are cloned where the filesystem supports it, and the rest is copied.
+    fn clone_or_copy_value(&mut self, value: &SnapshotValue<Value>) -> PubResult<ValueWriter> {
+        let supports_cloning = self.handle.dir_supports_file_cloning();
+        let block_size = self.handle.block_size();
+        let mut value_writer = self.new_value().begin()?;
+        let Nonzero(NonzeroValueLocation {
+            file_offset,
+            length,
+            ..
+        }) = value.location
+        else {
+            return Ok(value_writer);
+        };
+        let file_clone = value.file_clone().unwrap().lock().unwrap();
+        let src = &file_clone.file;
+        let mut cloned = 0;
+        let aligned_length = floored_multiple(length, block_size);
+        if supports_cloning && file_offset % block_size == 0 && aligned_length != 0 {
+            match value_writer.exclusive_file.clone_range_from(
+                src,
+                file_offset,
+                aligned_length,
+                block_size,
+            ) {
+                Ok(value_file_offset) => {
+                    value_writer.value_file_offset = value_file_offset;
+                    cloned = aligned_length;
+                }
+                // The range is reverted, so the value can still be copied.
+                Err(err) => debug!(?err, "cloning value range for append"),
+            }
+        }
+        let remaining = length - cloned;
+        if value_writer.copy_from_file(src, file_offset + cloned, remaining)? != remaining {
+            return Err(io::Error::new(
+                ErrorKind::UnexpectedEof,
+                "values file shrank while copying",
+            )
+            .into());
+        }
+        Ok(value_writer)
+    }
+
+    /// Gets an exclusive file that ends with the value at location, if it's not shared with
+    /// linked keys.
+    fn exclusive_file_for_append(
+        &mut self,
+        location: &NonzeroValueLocation,
+    ) -> Result<Option<ExclusiveFile>> {
+        let value_end = location.file_offset + location.length;
+        let key_count = self
+            .handle
+            .start_deferred_transaction_for_read()?
+            .location_key_count(&location.file_id, location.file_offset)?;
+        if key_count != 1 {
+            return Ok(None);
+        }
+        let file_id = location.file_id;
+        let mut exclusive_file = match self.exclusive_files.iter().position(|ef| ef.id == file_id) {
+            Some(index) => Some(self.exclusive_files.swap_remove(index)),
+            None => self.handle.exclusive_files.lock().unwrap().remove(&file_id),
+        };
+        if exclusive_file.is_none() {
+            exclusive_file = ExclusiveFile::open(file_path(self.handle.dir.path(), file_id))?;
+        }
+        let Some(mut exclusive_file) = exclusive_file else {
+            return Ok(None);
+        };
+        if exclusive_file.next_write_offset()? != value_end {
+            // Keep it around for new values.
+            self.exclusive_files.push(exclusive_file);
+            return Ok(None);
+        }
+        Ok(Some(exclusive_file))
+    }
+
     pub fn new_value<'writer>(&'writer mut self) -> BeginWriteValue<'writer, 'handle> {
         BeginWriteValue { batch: self }
     }
@@ -322,27 +980,64 @@ impl<'handle> BatchWriter<'handle> {
         });
     }
 
-    pub fn commit(self) -> Result<WriteCommitResult> {
+    /// Makes new_key reference the same stored value as existing_key on commit. This happens after
+    /// the batch's writes and renames, so existing_key can be written in the same batch. The commit
+    /// fails with NoSuchKey if existing_key doesn't exist by then.
+    pub fn link(&mut self, existing_key: Vec<u8>, new_key: Vec<u8>) {
+        self.links.push(KeyLink {
+            existing_key,
+            new_key,
+        });
+    }
+
+    /// Clears a fetch claim in the same transaction as the batch commit, so other handles waiting
+    /// on the fetch see the value as soon as the claim is gone.
+    fn release_fetch_claim(&mut self, key: Vec<u8>, claim_id: FetchClaimId) {
+        self.released_fetch_claims.push((key, claim_id));
+    }
+
+    pub fn commit(self) -> PubResult<WriteCommitResult> {
         self.commit_inner(|| {})
     }
 
-    fn commit_inner(mut self, before_write: impl Fn()) -> Result<WriteCommitResult> {
+    fn commit_inner(mut self, before_write: impl Fn()) -> PubResult<WriteCommitResult> {
         if flocking() {
             for ef in &mut self.exclusive_files {
                 assert!(ef.downgrade_lock()?);
             }
         }
         let mut transaction: OwnedTx = self.handle.start_immediate_transaction()?;
+        // Dropping the transaction on a failed precondition rolls back the entire batch.
+        for KeyPrecondition { key, expected } in self.preconditions.drain(..) {
+            let actual = transaction.key_generation(&key)?;
+            if actual != expected {
+                return Err(Error::WriteConflict {
+                    key,
+                    expected,
+                    actual,
+                });
+            }
+        }
         let mut write_commit_res = WriteCommitResult { count: 0 };
         for pw in self.pending_writes.drain(..) {
             before_write();
-            transaction.delete_key(&pw.key)?;
+            transaction.remove_key(&pw.key)?;
             transaction.insert_key(pw)?;
             write_commit_res.count += 1;
         }
         for vr in self.value_renames.drain(..) {
             transaction.rename_value(&vr.value, vr.new_key)?;
         }
+        for KeyLink {
+            existing_key,
+            new_key,
+        } in self.links.drain(..)
+        {
+            transaction.link_key(&existing_key, &new_key)?;
+        }
+        for (key, claim_id) in self.released_fetch_claims.drain(..) {
+            transaction.release_fetch_claim(&key, claim_id)?;
+        }
         // TODO: On error here, rewind the exclusive to undo any writes that just occurred.
         let work = transaction
             .commit(write_commit_res)
@@ -386,6 +1081,7 @@ type ValueLength = u64;
 pub struct Value {
     pub location: ValueLocation,
     last_used: Timestamp,
+    generation: Generation,
 }
 
 /// Storage location info for a non-zero-length value.
@@ -448,6 +1144,7 @@ impl Value {
         let file_offset: Option<u64> = row.get(1)?;
         let length = row.get(2)?;
         let last_used = row.get(3)?;
+        let generation = row.get(4)?;
         let location = if length == 0 {
             assert_eq!(file_id, None);
             assert_eq!(file_offset, None);
@@ -462,12 +1159,20 @@ impl Value {
         Ok(Value {
             location,
             last_used,
+            generation,
         })
     }
 
+    /// When the value was last used as of the read that returned it. Reads update last_used after
+    /// the snapshot is taken, so values from a Reader or read_single don't include their own
+    /// read.
     pub fn last_used(&self) -> Timestamp {
         self.last_used
     }
+
+    pub fn generation(&self) -> Generation {
+        self.generation
+    }
 }
 
 impl AsRef<Value> for Value {
@@ -491,13 +1196,18 @@ impl AsRef<Snapshot> for Snapshot {
 #[derive(Debug)]
 pub struct Snapshot {
     file_clones: HashMap<FileId, Arc<Mutex<FileClone>>>,
+    missing_ranges: MissingRanges,
 }
 
+/// Missing ranges of sparse values, by value location.
+type MissingRanges = HashMap<(FileId, u64), Vec<Range<u64>>>;
+
 #[derive(Debug)]
 pub struct SnapshotValue<V> {
     value: V,
     // This is Some if value is Nonzero.
     cloned_file: Option<Arc<Mutex<FileClone>>>,
+    missing_ranges: Vec<Range<u64>>,
 }
 
 impl<V> Deref for SnapshotValue<V> {
@@ -513,12 +1223,21 @@ impl Snapshot {
     where
         V: AsRef<Value>,
     {
+        let missing_ranges = match value.as_ref().location {
+            Nonzero(location) => self
+                .missing_ranges
+                .get(&(location.file_id, location.file_offset))
+                .cloned()
+                .unwrap_or_default(),
+            ZeroLength => vec![],
+        };
         SnapshotValue {
             cloned_file: value
                 .as_ref()
                 .file_id()
                 .map(|file_id| Arc::clone(self.file_clones.get(file_id).unwrap())),
             value,
+            missing_ranges,
         }
     }
 }
@@ -579,6 +1298,20 @@ where
         self.cloned_file.as_ref()
     }
 
+    /// The ranges of a sparse value that hadn't been written when the snapshot was taken, in
+    /// order. These read as zeroes.
+    pub fn missing_ranges(&self) -> &[Range<u64>] {
+        &self.missing_ranges
+    }
+
+    /// Whether all of range was written when the snapshot was taken.
+    pub fn is_range_present(&self, range: Range<u64>) -> bool {
+        !self
+            .missing_ranges
+            .iter()
+            .any(|missing| missing.start < range.end && range.start < missing.end)
+    }
+
     pub fn view<R>(&self, f: impl FnOnce(&[u8]) -> R) -> io::Result<R> {
         let value = self.value.as_ref();
         match value.location {
@@ -625,6 +1358,135 @@ where
         }
     }
 
+    /// Writes the value from offset to dst, which can be a file, pipe or socket. Where splice or
+    /// sendfile is supported, the data is sent straight from the snapshot's file clone without
+    /// passing through user space. Returns the number of bytes sent. If dst is nonblocking that
+    /// can be fewer than the rest of the value, and sending can be resumed from the new offset.
+    #[cfg(unix)]
+    pub fn send_to(&self, offset: u64, dst: impl AsFd) -> io::Result<u64> {
+        let Nonzero(NonzeroValueLocation {
+            file_offset,
+            length,
+            ..
+        }) = self.value.as_ref().location
+        else {
+            return Ok(0);
+        };
+        let dst = dst.as_fd();
+        // A handle of our own, so other reads of the file clone aren't held up by the transfer.
+        let file = self
+            .file_clone()
+            .unwrap()
+            .lock()
+            .unwrap()
+            .file
+            .try_clone()?;
+        let src_offset = file_offset + offset.min(length);
+        let length = length.saturating_sub(offset);
+        let mut sent = 0;
+        let res = (|| {
+            // splice only sends to pipes, and sendfile can't send to everything everywhere.
+            type KernelCopy = fn(&File, u64, BorrowedFd, u64) -> io::Result<u64>;
+            let mut kernel_copies: &[KernelCopy] = &[splice, sendfile];
+            while let Some(kernel_copy) = kernel_copies.first() {
+                if sent == length {
+                    return Ok(());
+                }
+                match kernel_copy(&file, src_offset + sent, dst, length - sent) {
+                    Ok(0) => return Ok(()),
+                    Ok(n) => sent += n,
+                    Err(err) if err.kind() == ErrorKind::Interrupted => {}
+                    Err(err) if is_zero_copy_unsupported(&err) => {
+                        kernel_copies = &kernel_copies[1..]
+                    }
+                    Err(err) => return Err(err),
+                }
+            }
+            let mut dst = File::from(dst.try_clone_to_owned()?);
+            let mut buf = vec![0; min(length - sent, 1 << 16) as usize];
+            while sent < length {
+                let n = min(length - sent, buf.len() as u64) as usize;
+                let n = match file.read_at(src_offset + sent, &mut buf[..n]) {
+                    Ok(0) => return Ok(()),
+                    Ok(n) => n,
+                    Err(err) if err.kind() == ErrorKind::Interrupted => continue,
+                    Err(err) => return Err(err),
+                };
+                match dst.write(&buf[..n]) {
+                    Ok(0) => return Err(ErrorKind::WriteZero.into()),
+                    Ok(n) => sent += n as u64,
+                    Err(err) if err.kind() == ErrorKind::Interrupted => {}
+                    Err(err) => return Err(err),
+                }
+            }
+            Ok(())
+        })();
+        match res {
+            // Report what was sent, so the caller can resume from there.
+            Err(err) if err.kind() == ErrorKind::WouldBlock && sent != 0 => Ok(sent),
+            Err(err) => Err(err),
+            Ok(()) => Ok(sent),
+        }
+    }
+
+    /// Writes the value to a standalone file at path, replacing any file there. If the value is
+    /// block-aligned in its values file, its whole blocks are cloned into the destination where
+    /// the filesystem supports it. The rest is copied, with copy_file_range where possible, and
+    /// missing ranges are left as holes.
+    pub fn export_to(&self, path: impl AsRef<Path>) -> PubResult<()> {
+        let mut dst = OpenOptions::new()
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .open(path)?;
+        let Nonzero(NonzeroValueLocation {
+            file_offset,
+            length,
+            ..
+        }) = self.value.as_ref().location
+        else {
+            return Ok(());
+        };
+        let mut file_clone = self.file_clone().unwrap().lock().unwrap();
+        let src = &mut file_clone.file;
+        let block_size = fd_min_hole_size(src)?;
+        let mut exported = 0;
+        let aligned_length = floored_multiple(length, block_size);
+        if file_offset % block_size == 0 && aligned_length != 0 {
+            match clone_file_range(src, file_offset, &dst, 0, aligned_length) {
+                Ok(()) => exported = aligned_length,
+                Err(err) if err.is_unsupported() || is_zero_copy_unsupported(&err) => {
+                    debug!(?err, "cloning value range for export")
+                }
+                Err(err) => return Err(err.into()),
+            }
+        }
+        // Holes in the values file and missing ranges are left as holes in the destination.
+        let data = data_ranges(src, file_offset + exported..file_offset + length)?;
+        let present = data.into_iter().flat_map(|range| {
+            subtract_ranges(
+                range.start - file_offset..range.end - file_offset,
+                &self.missing_ranges,
+            )
+        });
+        for present in present {
+            let present_length = present.end - present.start;
+            let copied = copy_range(
+                src,
+                file_offset + present.start,
+                &mut dst,
+                present.start,
+                present_length,
+            )?;
+            if copied != present_length {
+                return Err(anyhow!("values file ended during export").into());
+            }
+        }
+        // Extends over any trailing missing range.
+        dst.set_len(length)?;
+        Ok(())
+    }
+
     pub fn new_reader(&self) -> impl Read + '_ {
         positioned_io::Cursor::new(self)
     }
@@ -667,7 +1529,6 @@ struct ReadExtent {
     pub len: u64,
 }
 
-#[allow(dead_code)]
 fn floored_multiple<T>(value: T, multiple: T) -> T
 where
     T: Integer + Copy,
@@ -684,6 +1545,123 @@ where
     (value + multiple - T::one()) / multiple * multiple
 }
 
+/// Adds range to sorted, disjoint ranges, merging it with any it overlaps or touches.
+fn merge_range(ranges: &mut Vec<Range<u64>>, range: Range<u64>) {
+    if range.is_empty() {
+        return;
+    }
+    let mut merged = range;
+    ranges.retain(|other| {
+        if other.end < merged.start || other.start > merged.end {
+            return true;
+        }
+        merged = min(merged.start, other.start)..max(merged.end, other.end);
+        false
+    });
+    let index = ranges.partition_point(|other| other.start < merged.start);
+    ranges.insert(index, merged);
+}
+
+/// Returns the parts of range not covered by the sorted, disjoint ranges.
+fn subtract_ranges(range: Range<u64>, sorted: &[Range<u64>]) -> Vec<Range<u64>> {
+    let mut remaining = vec![];
+    let mut start = range.start;
+    for other in sorted {
+        if other.end <= start || other.start >= range.end {
+            continue;
+        }
+        if other.start > start {
+            remaining.push(start..other.start);
+        }
+        start = max(start, other.end);
+    }
+    if start < range.end {
+        remaining.push(start..range.end);
+    }
+    remaining
+}
+
+/// Clones the file at src_path to dst_path, or where that's unsupported, copies its data regions
+/// leaving holes as holes.
+fn clone_or_copy_file(src_path: &Path, dst_path: &Path) -> io::Result<()> {
+    match clonefile(src_path, dst_path) {
+        Err(err) if err.is_unsupported() => debug!(?err, ?src_path, "cloning file"),
+        default => return default,
+    }
+    let mut src = File::open(src_path)?;
+    let length = src.metadata()?.len();
+    let mut dst = OpenOptions::new()
+        .write(true)
+        .create(true)
+        .truncate(true)
+        .open(dst_path)?;
+    dst.set_sparse(true)?;
+    for data in data_ranges(&mut src, 0..length)? {
+        let data_length = data.end - data.start;
+        if copy_range(&src, data.start, &mut dst, data.start, data_length)? != data_length {
+            return Err(io::Error::new(
+                ErrorKind::UnexpectedEof,
+                "file shrank while copying",
+            ));
+        }
+    }
+    dst.set_len(length)
+}
+
+/// Returns the data regions of file within range, skipping holes.
+fn data_ranges(file: &mut File, range: Range<u64>) -> io::Result<Vec<Range<u64>>> {
+    let mut data = vec![];
+    for region in seekhole::Iter::starting_at(file, range.start) {
+        let region = region?;
+        if region.start >= range.end {
+            break;
+        }
+        if region.region_type == seekhole::Data {
+            data.push(max(region.start, range.start)..min(region.end, range.end));
+        }
+    }
+    Ok(data)
+}
+
+/// Copies up to length bytes from src to dst at the given offsets, in the kernel where that's
+/// supported. Returns the number of bytes copied, which is less than length if src ends first.
+fn copy_range(
+    src: &File,
+    src_offset: u64,
+    dst: &mut File,
+    dst_offset: u64,
+    length: u64,
+) -> io::Result<u64> {
+    let mut copied = 0;
+    while copied < length {
+        match copy_file_range(
+            src,
+            src_offset + copied,
+            dst,
+            dst_offset + copied,
+            length - copied,
+        ) {
+            Ok(0) => return Ok(copied),
+            Ok(n) => copied += n,
+            Err(err) if is_zero_copy_unsupported(&err) => break,
+            Err(err) => return Err(err),
+        }
+    }
+    let mut buf = vec![0; min(length - copied, 1 << 16) as usize];
+    while copied < length {
+        let want = min(buf.len() as u64, length - copied) as usize;
+        let n = match src.read_at(src_offset + copied, &mut buf[..want]) {
+            Ok(0) => break,
+            Ok(n) => n,
+            Err(err) if err.kind() == ErrorKind::Interrupted => continue,
+            Err(err) => return Err(err),
+        };
+        positioned_io::WriteAt::write_all_at(dst, dst_offset + copied, &buf[..n])?;
+        copied += n as u64;
+    }
+    Ok(copied)
+}
+
 fn open_file_id(options: &OpenOptions, dir: &Path, file_id: &FileId) -> io::Result<File> {
     options.open(file_path(dir, file_id))
 }
@@ -712,8 +1690,58 @@ fn random_file_name(prefix: &str) -> OsString {
 }
 
 pub const MANIFEST_DB_FILE_NAME: &str = "manifest.db";
+const CLONE_LOCK_FILE_NAME: &str = "clone.lock";
+
+/// Opens the lock file that coordinates reads with punching. Readers hold it shared from before
+/// their manifest transaction until they've cloned or locked the files they read from. Punching
+/// requires it exclusively, so a value can't be punched between a reader seeing it and cloning it.
+/// Each holder needs its own open file for the locks to conflict.
+fn open_clone_lock(dir: &Path) -> io::Result<File> {
+    OpenOptions::new()
+        .read(true)
+        .write(true)
+        .create(true)
+        .truncate(false)
+        .open(dir.join(CLONE_LOCK_FILE_NAME))
+}
 
-struct PunchValueConstraints {
+/// The byte of the clone lock file that readers hold shared and punching holds exclusively.
+const CLONE_LOCK_OFFSET: u64 = 0;
+/// The byte of the clone lock file held exclusively by a punch waiting for reads to finish. New
+/// readers wait for it, so overlapping reads can't keep punching out indefinitely.
+const PUNCH_PENDING_LOCK_OFFSET: u64 = 1;
+/// How long punching waits for reads in progress before giving up and trying again later. It's
+/// bounded so that a thread that starts a read while holding another can't wait on itself.
+const PUNCH_PENDING_TIMEOUT: Duration = Duration::from_millis(100);
+
+/// Returns the clone lock held shared, once any punch waiting for reads has had its turn.
+fn lock_clone_lock_for_read(dir: &Path) -> io::Result<File> {
+    let file = open_clone_lock(dir)?;
+    assert!(file.lock_segment(LockShared, Some(1), PUNCH_PENDING_LOCK_OFFSET)?);
+    assert!(file.lock_segment(LockShared, Some(1), CLONE_LOCK_OFFSET)?);
+    file.lock_segment(UnlockNonblock, Some(1), PUNCH_PENDING_LOCK_OFFSET)?;
+    Ok(file)
+}
+
+/// Returns the clone lock held exclusively, or None if reads didn't finish within
+/// PUNCH_PENDING_TIMEOUT or another punch is waiting already.
+fn lock_clone_lock_for_punch(dir: &Path) -> io::Result<Option<File>> {
+    let file = open_clone_lock(dir)?;
+    if !file.lock_segment(LockExclusiveNonblock, Some(1), PUNCH_PENDING_LOCK_OFFSET)? {
+        return Ok(None);
+    }
+    let deadline = std::time::Instant::now() + PUNCH_PENDING_TIMEOUT;
+    while !file.lock_segment(LockExclusiveNonblock, Some(1), CLONE_LOCK_OFFSET)? {
+        if std::time::Instant::now() >= deadline {
+            return Ok(None);
+        }
+        std::thread::sleep(Duration::from_millis(1));
+    }
+    Ok(Some(file))
+}
+
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct PunchValueConstraints {
     greedy_start: bool,
     check_hole: bool,
     greedy_end: bool,
@@ -721,6 +1749,20 @@ struct PunchValueConstraints {
     allow_remove: bool,
 }
 
+impl PunchValueConstraints {
+    /// For evicted extents. These are block aligned and must not be expanded like deleted values,
+    /// since the rest of the value is still in use.
+    pub(crate) fn extent() -> Self {
+        Self {
+            greedy_start: false,
+            greedy_end: false,
+            allow_truncate: false,
+            allow_remove: false,
+            check_hole: true,
+        }
+    }
+}
+
 impl Default for PunchValueConstraints {
     fn default() -> Self {
         Self {
@@ -743,7 +1785,8 @@ struct PunchValueOptions<'a> {
     constraints: PunchValueConstraints,
 }
 
-// Can't do this as &mut self for dumb Rust reasons.
+// Can't do this as &mut self for dumb Rust reasons. The clone lock must be held exclusively, see
+// lock_clone_lock_for_punch.
 fn punch_value(opts: PunchValueOptions) -> Result<bool> {
     let PunchValueOptions {
         dir,
@@ -930,6 +1973,17 @@ fn make_to_usize_io_error() -> io::Error {
 const TO_USIZE_IO_ERROR_KIND: ErrorKind = InvalidInput;
 const TO_USIZE_IO_ERR_PAYLOAD: &str = "can't convert to usize";
 
+/// Returns the exclusive end of the range of keys that start with prefix, or None if there's no
+/// upper bound.
+fn prefix_range_end(prefix: &[u8]) -> Option<Vec<u8>> {
+    let mut end = prefix.to_owned();
+    if inc_big_endian_array(&mut end) {
+        Some(end)
+    } else {
+        None
+    }
+}
+
 /// Increments the right most byte, overflowing leftwards. Returns false if incrementing the array
 /// overflows the available bytes.
 fn inc_big_endian_array(arr: &mut [u8]) -> bool {
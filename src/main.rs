@@ -1,6 +1,7 @@
 use std::cmp::max;
 use std::ffi::OsString;
 use std::fs::{File, OpenOptions};
+use std::io::{stdin, stdout, BufReader, BufWriter};
 use std::path::{Path, PathBuf};
 
 use anyhow::{anyhow, bail, Context};
@@ -42,9 +43,42 @@ enum DatabaseCommands {
     },
     ListKeys {
         prefix: String,
+        #[arg(long)]
+        start_after: Option<String>,
+        #[arg(long)]
+        limit: Option<usize>,
+        #[arg(short, long)]
+        reverse: bool,
+    },
+    /// Imports every file under root, keyed by prefix and the path relative to root.
+    Import {
+        root: PathBuf,
+        #[arg(long, default_value = "")]
+        prefix: String,
+        /// Move files into the cache instead of cloning or copying them.
+        #[arg(long)]
+        rename: bool,
+        #[arg(long)]
+        batch_size: Option<usize>,
+    },
+    /// Writes the items with keys starting with prefix to a portable archive.
+    Export {
+        #[arg(long, default_value = "")]
+        prefix: String,
+        /// Write the archive to this file instead of stdout.
+        #[arg(short, long)]
+        output: Option<PathBuf>,
+    },
+    /// Restores the items from an archive written by export.
+    Restore {
+        /// Read the archive from this file instead of stdin.
+        input: Option<PathBuf>,
     },
     ReadKey {
         key: String,
+        /// Write the value to this file instead of stdout, cloning blocks where possible.
+        #[arg(short, long)]
+        output: Option<PathBuf>,
     },
     PrintMissingHoles {
         file_id: Option<PathBuf>,
@@ -89,17 +123,84 @@ fn main() -> anyhow::Result<()> {
                     handle.single_write_from(key.to_os_string().into_encoded_bytes(), file)?;
                     Ok(())
                 }
-                ListKeys { prefix } => {
-                    let items = handle.list_items(prefix.as_bytes())?;
-                    for item in items {
+                ListKeys {
+                    prefix,
+                    start_after,
+                    limit,
+                    reverse,
+                } => {
+                    let options = ListOptions {
+                        prefix: prefix.into_bytes(),
+                        start_after: start_after.map(String::into_bytes),
+                        limit,
+                        reverse,
+                    };
+                    for item in handle.iter_items(options) {
+                        let item = item?;
                         println!("{}", unsafe { std::str::from_utf8_unchecked(&item.key) })
                     }
                     Ok(())
                 }
-                ReadKey { key } => {
+                Import {
+                    root,
+                    prefix,
+                    rename,
+                    batch_size,
+                } => {
+                    let options = ImportOptions {
+                        rename,
+                        batch_size,
+                        progress: Some(Box::new(|stats: &BulkOpStats| {
+                            eprintln!(
+                                "imported {} files, {} bytes",
+                                stats.count, stats.value_length_sum
+                            )
+                        })),
+                    };
+                    handle.import_dir(root, prefix.as_bytes(), options)?;
+                    Ok(())
+                }
+                Export { prefix, output } => {
+                    let stats = match output {
+                        Some(path) => handle.export(
+                            BufWriter::new(
+                                File::create(&path)
+                                    .with_context(|| format!("creating {}", path.display()))?,
+                            ),
+                            prefix.as_bytes(),
+                        )?,
+                        None => {
+                            handle.export(BufWriter::new(stdout().lock()), prefix.as_bytes())?
+                        }
+                    };
+                    eprintln!(
+                        "exported {} items, {} bytes",
+                        stats.count, stats.value_length_sum
+                    );
+                    Ok(())
+                }
+                Restore { input } => {
+                    let stats = match input {
+                        Some(path) => handle.restore(BufReader::new(
+                            File::open(&path)
+                                .with_context(|| format!("opening {}", path.display()))?,
+                        ))?,
+                        None => handle.restore(BufReader::new(stdin().lock()))?,
+                    };
+                    eprintln!(
+                        "restored {} items, {} bytes",
+                        stats.count, stats.value_length_sum
+                    );
+                    Ok(())
+                }
+                ReadKey { key, output } => {
                     let Some(value) = handle.read_single(key.as_bytes())? else {
                         bail!("key not found")
                     };
+                    if let Some(output) = output {
+                        value.export_to(output)?;
+                        return Ok(());
+                    }
                     let mut r = value.new_reader();
                     let n = std::io::copy(&mut r, &mut std::io::stdout())?;
                     // dbg!(n, value.length());
@@ -0,0 +1,239 @@
+//! Snapshots that outlive the process that took them. Each is a backup of the cache (see
+//! Handle::backup_to) in a directory named after it, so any Handle on the same cache directory can
+//! open it. They're removed when released, or by Handle::cleanup_snapshots once they expire.
+
+use rusqlite::OpenFlags;
+
+use super::*;
+
+const NAMED_SNAPSHOT_DIR_NAME_PREFIX: &str = "named-snapshot-";
+/// Held exclusively in the directory a named snapshot is built in until the build is done.
+const BUILD_LOCK_FILE_NAME: &str = "build.lock";
+
+/// The path of the directory for the named snapshot in a cache directory.
+fn named_snapshot_dir(dir: &Path, name: &str) -> PubResult<PathBuf> {
+    let valid = !name.is_empty()
+        && name
+            .bytes()
+            .all(|b| b.is_ascii_alphanumeric() || b"-_.".contains(&b))
+        && !name.starts_with('.');
+    if !valid {
+        return Err(io::Error::new(InvalidInput, format!("invalid snapshot name {name:?}")).into());
+    }
+    Ok(dir.join(format!("{NAMED_SNAPSHOT_DIR_NAME_PREFIX}{name}")))
+}
+
+fn now() -> Timestamp {
+    Timestamp(chrono::Utc::now().naive_utc())
+}
+
+fn open_manifest(snapshot_dir: &Path) -> rusqlite::Result<Connection> {
+    Connection::open_with_flags(
+        snapshot_dir.join(MANIFEST_DB_FILE_NAME),
+        OpenFlags::SQLITE_OPEN_READ_ONLY | OpenFlags::SQLITE_OPEN_NO_MUTEX,
+    )
+}
+
+fn query_expires(conn: &Connection) -> rusqlite::Result<Option<Timestamp>> {
+    conn.query_row("select expires from named_snapshot", [], |row| row.get(0))
+}
+
+pub(crate) fn create(handle: &Handle, name: &str, ttl: Option<Duration>) -> PubResult<()> {
+    let dir = named_snapshot_dir(handle.dir(), name)?;
+    if dir.exists() {
+        return Err(io::Error::new(
+            ErrorKind::AlreadyExists,
+            format!("snapshot {name:?} already exists"),
+        )
+        .into());
+    }
+    // Built inside a directory that isn't recognized as a snapshot, so it only appears once
+    // complete. The lock tells Handle::cleanup_snapshots the build is still going.
+    let build_dir = tempfile::Builder::new()
+        .prefix(&format!(".{NAMED_SNAPSHOT_DIR_NAME_PREFIX}"))
+        .tempdir_in(handle.dir())?;
+    // The lock file is only given its name once it's locked, so cleanup never sees it unlocked
+    // while the build is live.
+    let unlocked_path = build_dir.path().join(format!("{BUILD_LOCK_FILE_NAME}.new"));
+    let build_lock = File::create(&unlocked_path)?;
+    if !build_lock.lock_max_segment(LockExclusiveNonblock)? {
+        return Err(anyhow!("named snapshot build lock is taken").into());
+    }
+    fs::rename(&unlocked_path, build_dir.path().join(BUILD_LOCK_FILE_NAME))?;
+    let snapshot_dir = build_dir.path().join("snapshot");
+    handle.backup_to(&snapshot_dir)?;
+    let expires = ttl
+        .map(|ttl| chrono::Duration::from_std(ttl).map(|ttl| Timestamp(now().0 + ttl)))
+        .transpose()
+        .context("snapshot ttl")?;
+    let conn = Connection::open(snapshot_dir.join(MANIFEST_DB_FILE_NAME))?;
+    // Readers open it read-only, which works best without a WAL.
+    conn.pragma_update(None, "journal_mode", "delete")?;
+    conn.execute_batch("create table named_snapshot (expires integer) strict")?;
+    conn.execute("insert into named_snapshot values (?)", [expires])?;
+    drop(conn);
+    // Renaming fails if another snapshot with this name was completed meanwhile.
+    fs::rename(&snapshot_dir, &dir)?;
+    Ok(())
+}
+
+pub(crate) fn release(handle: &Handle, name: &str) -> PubResult<bool> {
+    match fs::remove_dir_all(named_snapshot_dir(handle.dir(), name)?) {
+        Ok(()) => Ok(true),
+        Err(err) if err.kind() == ErrorKind::NotFound => Ok(false),
+        Err(err) => Err(err.into()),
+    }
+}
+
+/// Removes the named snapshots in dir that have expired, and what's left of builds that didn't
+/// finish.
+pub(crate) fn delete_expired(dir: &Path) -> Result<()> {
+    let now = now();
+    for entry in read_dir(dir)? {
+        let entry = entry?;
+        if !entry.file_type()?.is_dir() {
+            continue;
+        }
+        let file_name = entry.file_name();
+        let file_name = file_name.to_string_lossy();
+        let path = entry.path();
+        if file_name.starts_with(NAMED_SNAPSHOT_DIR_NAME_PREFIX) {
+            let expires = match open_manifest(&path).and_then(|conn| query_expires(&conn)) {
+                Ok(expires) => expires,
+                Err(err) => {
+                    warn!(?path, %err, "reading named snapshot expiry");
+                    continue;
+                }
+            };
+            if expires.is_some_and(|expires| expires <= now) {
+                let res = fs::remove_dir_all(&path);
+                debug!("removing expired named snapshot {:?}: {:?}", path, res);
+            }
+        } else if file_name.starts_with(&format!(".{NAMED_SNAPSHOT_DIR_NAME_PREFIX}")) {
+            match build_abandoned(&path) {
+                Ok(true) => {
+                    let res = fs::remove_dir_all(&path);
+                    debug!(
+                        "removing abandoned named snapshot build {:?}: {:?}",
+                        path, res
+                    );
+                }
+                Ok(false) => {}
+                Err(err) => warn!(?path, %err, "checking named snapshot build"),
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Whether the build in build_dir was left by a process that stopped before finishing it. A build
+/// whose lock file doesn't exist yet is treated as still going.
+fn build_abandoned(build_dir: &Path) -> io::Result<bool> {
+    let lock = match OpenOptions::new()
+        .write(true)
+        .open(build_dir.join(BUILD_LOCK_FILE_NAME))
+    {
+        Err(err) if err.kind() == ErrorKind::NotFound => return Ok(false),
+        res => res?,
+    };
+    lock.lock_max_segment(LockExclusiveNonblock)
+}
+
+/// A named snapshot opened for reading. Values are read from the snapshot's own files, so it stays
+/// readable even if the snapshot is released meanwhile, on platforms that allow removing open
+/// files.
+pub struct NamedSnapshot {
+    name: String,
+    conn: Connection,
+    expires: Option<Timestamp>,
+    // Every values file the snapshot refers to, opened with it.
+    file_clones: FileCloneCache,
+}
+
+impl Debug for NamedSnapshot {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("NamedSnapshot")
+            .field("name", &self.name)
+            .field("expires", &self.expires)
+            .finish_non_exhaustive()
+    }
+}
+
+impl NamedSnapshot {
+    pub(crate) fn open(handle: &Handle, name: &str) -> PubResult<Self> {
+        let dir = named_snapshot_dir(handle.dir(), name)?;
+        if !dir.exists() {
+            return Err(Error::NoSuchSnapshot);
+        }
+        let conn = open_manifest(&dir)?;
+        let expires = query_expires(&conn)?;
+        if expires.is_some_and(|expires| expires <= now()) {
+            return Err(Error::NoSuchSnapshot);
+        }
+        // Opened up front so values can still be read if the snapshot is released.
+        let mut file_clones = FileCloneCache::default();
+        for file_id in ReadTransactionOwned(conn.unchecked_transaction()?).referenced_file_ids()? {
+            let mut file = File::open(file_path(&dir, file_id))?;
+            let len = file.seek(End(0))?;
+            let file_clone = FileClone {
+                file,
+                tempdir: None,
+                mmap: None,
+                len,
+            };
+            file_clones.insert(file_id, Arc::new(Mutex::new(file_clone)));
+        }
+        Ok(Self {
+            name: name.to_owned(),
+            conn,
+            expires,
+            file_clones,
+        })
+    }
+
+    fn file_clone(&self, file_id: &FileId) -> io::Result<Arc<Mutex<FileClone>>> {
+        self.file_clones.get(file_id).cloned().ok_or_else(|| {
+            io::Error::new(
+                ErrorKind::NotFound,
+                format!("values file {file_id} not in named snapshot"),
+            )
+        })
+    }
+
+    fn read_tx(&self) -> rusqlite::Result<ReadTransactionOwned<'_>> {
+        Ok(ReadTransactionOwned(self.conn.unchecked_transaction()?))
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// When the snapshot expires, or None if it lasts until it's released.
+    pub fn expires(&self) -> Option<Timestamp> {
+        self.expires
+    }
+
+    /// Returns the value key had when the snapshot was taken.
+    pub fn read(&self, key: &[u8]) -> PubResult<Option<SnapshotValue<Value>>> {
+        let tx = self.read_tx()?;
+        let Some(value) = tx.key_value(key)? else {
+            return Ok(None);
+        };
+        let (cloned_file, missing_ranges) = match value.location {
+            Nonzero(location) => (
+                Some(self.file_clone(&location.file_id)?),
+                tx.missing_ranges(&location.file_id, location.file_offset)?,
+            ),
+            ZeroLength => (None, vec![]),
+        };
+        Ok(Some(SnapshotValue {
+            value,
+            cloned_file,
+            missing_ranges,
+        }))
+    }
+
+    pub fn list_items(&self, prefix: &[u8]) -> PubResult<Vec<Item>> {
+        self.read_tx()?.list_items(prefix)
+    }
+}
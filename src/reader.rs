@@ -1,51 +1,110 @@
+use std::ops::{Range, RangeBounds};
+
 use super::*;
+use crate::ownedtx::OwnedReadTx;
 
 // BTree possibly so we can merge extents in the future.
 type Reads = HashMap<FileId, BTreeSet<ReadExtent>>;
 
 pub struct Reader<'handle> {
-    pub(crate) owned_tx: OwnedTx<'handle>,
+    pub(crate) owned_tx: OwnedReadTx<'handle>,
     pub(crate) handle: &'handle Handle,
     pub(crate) reads: Reads,
+    pub(crate) missing_ranges: MissingRanges,
+    /// Held shared until the files read from are cloned or locked, see open_clone_lock.
+    pub(crate) clone_lock: File,
+    /// Keys to update last_used for once the snapshot is taken.
+    pub(crate) touches: Vec<Touch>,
+}
+
+/// A key read by a Reader, and the part of its value that was used.
+pub(crate) struct Touch {
+    pub(crate) key: Vec<u8>,
+    pub(crate) generation: Generation,
+    pub(crate) range: Option<Range<u64>>,
 }
 
 impl<'a> Reader<'a> {
     pub fn add(&mut self, key: &[u8]) -> rusqlite::Result<Option<Value>> {
-        let res = self.owned_tx.touch_for_read(key);
-        match res {
-            Ok(value) => {
-                if let Nonzero(NonzeroValueLocation {
-                    file_offset,
-                    length,
-                    file_id,
-                }) = value.location
-                {
-                    let file = self.reads.entry(file_id);
-                    file.or_default().insert(ReadExtent {
-                        offset: file_offset,
-                        len: length,
-                    });
-                }
-                Ok(Some(value))
+        self.add_inner(key, None)
+    }
+
+    /// Like add, but only the part of the value in range is marked as used for partial eviction.
+    pub fn add_range(&mut self, key: &[u8], range: Range<u64>) -> rusqlite::Result<Option<Value>> {
+        self.add_inner(key, Some(range))
+    }
+
+    fn add_inner(
+        &mut self,
+        key: &[u8],
+        range: Option<Range<u64>>,
+    ) -> rusqlite::Result<Option<Value>> {
+        let Some(value) = self.owned_tx.key_value(key)? else {
+            return Ok(None);
+        };
+        self.touches.push(Touch {
+            key: key.to_vec(),
+            generation: value.generation(),
+            range,
+        });
+        if let Nonzero(NonzeroValueLocation {
+            file_offset,
+            length,
+            file_id,
+        }) = value.location
+        {
+            let file = self.reads.entry(file_id);
+            file.or_default().insert(ReadExtent {
+                offset: file_offset,
+                len: length,
+            });
+            let missing_ranges = self.owned_tx.missing_ranges(&file_id, file_offset)?;
+            if !missing_ranges.is_empty() {
+                self.missing_ranges
+                    .insert((file_id, file_offset), missing_ranges);
             }
-            Err(QueryReturnedNoRows) => Ok(None),
-            Err(err) => Err(err),
         }
+        Ok(Some(value))
     }
 
-    /// Takes a snapshot and commits the read transaction.
+    /// Takes a snapshot, then updates last_used for the keys that were read. The read transaction
+    /// ends first, so commits can proceed while files are cloned. The clone lock ensures the
+    /// values read aren't punched in the meantime. Updating last_used is best effort: if it fails,
+    /// the snapshot is still returned.
     pub fn begin(self) -> Result<Snapshot> {
-        let file_clones = self.clone_files().context("cloning files")?;
-        self.owned_tx
-            .commit(())
-            .context("committing transaction")?
-            .complete();
-        Ok(Snapshot { file_clones })
+        let Self {
+            owned_tx,
+            handle,
+            reads,
+            missing_ranges,
+            clone_lock,
+            touches,
+        } = self;
+        drop(owned_tx);
+        let file_clones = Self::clone_files(handle, &reads).context("cloning files")?;
+        drop(clone_lock);
+        if let Err(err) = handle.touch_for_read(touches) {
+            warn!("updating last used after read: {:#}", err);
+        }
+        Ok(Snapshot {
+            file_clones,
+            missing_ranges,
+        })
+    }
+
+    /// Takes a snapshot of the values added so far, keeping the transaction open so more can be
+    /// read. last_used isn't updated for them.
+    pub(crate) fn snapshot_without_touching(&mut self) -> Result<Snapshot> {
+        let file_clones = Self::clone_files(self.handle, &self.reads).context("cloning files")?;
+        self.reads.clear();
+        self.touches.clear();
+        Ok(Snapshot {
+            file_clones,
+            missing_ranges: std::mem::take(&mut self.missing_ranges),
+        })
     }
 
-    fn clone_files(&self) -> Result<FileCloneCache> {
-        let handle = self.handle;
-        let reads = &self.reads;
+    fn clone_files(handle: &Handle, reads: &Reads) -> Result<FileCloneCache> {
         let mut tempdir = None;
         let mut file_clones: FileCloneCache = Default::default();
         // This isn't needed if file cloning is disabled...
@@ -54,7 +113,8 @@ impl<'a> Reader<'a> {
         for (file_id, extents) in reads {
             file_clones.insert(
                 *file_id,
-                self.get_file_clone(
+                Self::get_file_clone(
+                    handle,
                     file_id,
                     &mut tempdir,
                     handle_clones,
@@ -71,8 +131,25 @@ impl<'a> Reader<'a> {
         self.owned_tx.list_items(prefix)
     }
 
-    fn get_file_clone(
+    pub fn list_items_page(&self, options: &ListOptions) -> PubResult<Vec<Item>> {
+        self.owned_tx.list_items_page(options)
+    }
+
+    pub fn list_range(
         &self,
+        range: impl RangeBounds<[u8]>,
+        reverse: bool,
+        limit: Option<usize>,
+    ) -> PubResult<Vec<Item>> {
+        self.owned_tx.list_range(range, reverse, limit)
+    }
+
+    pub fn count_range(&self, range: impl RangeBounds<[u8]>) -> PubResult<u64> {
+        self.owned_tx.count_range(range)
+    }
+
+    fn get_file_clone(
+        handle: &Handle,
         file_id: &FileId,
         tempdir: &mut Option<Arc<TempDir>>,
         cache: &mut FileCloneCache,
@@ -90,17 +167,16 @@ impl<'a> Reader<'a> {
                 return Ok(ret.clone());
             }
         }
-        if self.handle.dir_supports_file_cloning() {
-            match self.clone_file(file_id, tempdir, cache, src_dir) {
+        if handle.dir_supports_file_cloning() {
+            match Self::clone_file(file_id, tempdir, cache, src_dir) {
                 Err(err) if err.root_cause_is_unsupported_filesystem() => (),
                 default => return default,
             }
         }
-        self.get_file_for_read_by_segment_locking(file_id, read_extents)
+        Self::get_file_for_read_by_segment_locking(handle, file_id, read_extents)
     }
 
     fn clone_file(
-        &self,
         file_id: &FileId,
         tempdir: &mut Option<Arc<TempDir>>,
         cache: &mut FileCloneCache,
@@ -117,15 +193,6 @@ impl<'a> Reader<'a> {
             }
         };
         let src_path = file_path(src_dir, file_id);
-        // TODO: In order for value files to support truncation, a shared or exclusive lock would
-        // need to be taken before cloning. I don't think this is possible, we would have to wait
-        // for anyone holding an exclusive lock to release it. Handles already cache these, plus
-        // Writers could hold them for a long time while writing. Then we need a separate cloning
-        // lock. Also distinct Handles, even across processes can own each exclusive file.
-        if false {
-            let src_file = OpenOptions::new().read(true).open(&src_path)?;
-            assert!(src_file.lock_max_segment(LockSharedNonblock)?);
-        }
         let tempdir_path = tempdir.path();
         let dst_path = file_path(tempdir_path, file_id);
         clonefile(&src_path, &dst_path).context("cloning file")?;
@@ -166,11 +233,11 @@ impl<'a> Reader<'a> {
     }
 
     fn get_file_for_read_by_segment_locking(
-        &self,
+        handle: &Handle,
         file_id: &FileId,
         read_extents: &BTreeSet<ReadExtent>,
     ) -> PubResult<Arc<Mutex<FileClone>>> {
-        let mut file = open_file_id(OpenOptions::new().read(true), self.handle.dir(), file_id)?;
+        let mut file = open_file_id(OpenOptions::new().read(true), handle.dir(), file_id)?;
 
         Self::lock_read_extents(&file, read_extents.iter())?;
         let len = file.seek(std::io::SeekFrom::End(0))?;
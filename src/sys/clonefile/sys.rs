@@ -143,3 +143,57 @@ pub fn fclonefile_noflags(src_file: &File, dst_path: &Path) -> Result<(), Native
         }
     }
 }
+
+/// Clones length bytes of src at src_offset into dst at dst_offset, sharing the blocks. Offsets
+/// and length must be multiples of the filesystem block size, except that length can reach the end
+/// of src.
+#[allow(unused_variables)]
+pub fn clone_file_range(
+    src_file: &File,
+    src_offset: u64,
+    dst_file: &File,
+    dst_offset: u64,
+    length: u64,
+) -> Result<(), NativeIoError> {
+    cfg_if! {
+        if #[cfg(windows)] {
+            use std::ffi::c_void;
+            let data = DUPLICATE_EXTENTS_DATA {
+                FileHandle: HANDLE(src_file.as_raw_handle() as isize),
+                SourceFileOffset: src_offset as i64,
+                TargetFileOffset: dst_offset as i64,
+                ByteCount: length as i64,
+            };
+            let data_ptr = &data as *const _ as *const c_void;
+            unsafe {
+                DeviceIoControl(
+                    std_handle_to_windows(dst_file.as_raw_handle()),
+                    FSCTL_DUPLICATE_EXTENTS_TO_FILE,
+                    Some(data_ptr),
+                    std::mem::size_of_val(&data) as u32,
+                    None,
+                    0,
+                    None,
+                    None,
+                )
+            }?;
+            Ok(())
+        } else if #[cfg(target_os = "linux")] {
+            let range = libc::file_clone_range {
+                src_fd: src_file.as_raw_fd().into(),
+                src_offset,
+                src_length: length,
+                dest_offset: dst_offset,
+            };
+            #[allow(clippy::useless_conversion)]
+            let request = libc::FICLONERANGE.try_into().unwrap();
+            let rv = unsafe { libc::ioctl(dst_file.as_raw_fd(), request, &range) };
+            if rv == -1 {
+                return Err(last_errno());
+            }
+            Ok(())
+        } else {
+            Err(io::Error::from_raw_os_error(EOPNOTSUPP))
+        }
+    }
+}
@@ -7,6 +7,7 @@ mod flock;
 mod pathconf;
 mod punchfile;
 pub mod seekhole;
+mod zerocopy;
 
 use std::fs::File;
 
@@ -14,6 +15,7 @@ pub use clonefile::*;
 pub use flock::*;
 pub(crate) use pathconf::*;
 pub use punchfile::*;
+pub use zerocopy::*;
 
 use crate::env::{emulate_freebsd, flocking};
 
@@ -34,6 +36,7 @@ cfg_if! {
         pub(crate) use std::os::unix::prelude::OsStrExt;
         pub(crate) use std::os::fd::AsRawFd;
         pub(crate) use std::os::fd::AsFd;
+        pub(crate) use std::os::fd::BorrowedFd;
         pub type NativeIoError = std::io::Error;
     }
 }
@@ -50,6 +53,37 @@ cfg_if! {
     }
 }
 
+/// Renames src to dst, failing with AlreadyExists instead of replacing dst. Where the platform or
+/// filesystem can't do that atomically, dst is checked for first.
+pub(crate) fn rename_no_replace(src: &Path, dst: &Path) -> io::Result<()> {
+    cfg_if! {
+        if #[cfg(all(target_os = "linux", target_env = "gnu"))] {
+            use nix::fcntl::{renameat2, RenameFlags};
+            match renameat2(None, src, None, dst, RenameFlags::RENAME_NOREPLACE) {
+                Err(nix::errno::Errno::EINVAL) => {}
+                res => return Ok(res?),
+            }
+        } else if #[cfg(target_os = "macos")] {
+            let src_c = std::ffi::CString::new(src.as_os_str().as_bytes())?;
+            let dst_c = std::ffi::CString::new(dst.as_os_str().as_bytes())?;
+            if 0 == unsafe { libc::renamex_np(src_c.as_ptr(), dst_c.as_ptr(), libc::RENAME_EXCL) } {
+                return Ok(());
+            }
+            let err = io::Error::last_os_error();
+            if err.raw_os_error() != Some(libc::ENOTSUP) {
+                return Err(err);
+            }
+        }
+    }
+    if dst.try_exists()? {
+        return Err(io::Error::new(
+            ErrorKind::AlreadyExists,
+            format!("{dst:?} already exists"),
+        ));
+    }
+    fs::rename(src, dst)
+}
+
 pub trait SparseFile {
     fn set_sparse(&self, set_sparse: bool) -> io::Result<()>;
 }
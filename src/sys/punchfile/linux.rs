@@ -22,3 +22,24 @@ pub fn punchfile(file: &File, offset: u64, length: u64) -> io::Result<()> {
     }
     Ok(())
 }
+
+/// Allocates blocks for the given range without changing the file size, so later writes to it
+/// can't fail for lack of space. Filesystems that don't support it are ignored.
+pub fn preallocate(file: &File, offset: u64, length: u64) -> io::Result<()> {
+    // fallocate rejects empty ranges.
+    if length == 0 {
+        return Ok(());
+    }
+    let fd = file.as_fd().as_raw_fd();
+    let too_large = |_| Error::new(io::ErrorKind::InvalidInput, "preallocation range too large");
+    let offset = offset.try_into().map_err(too_large)?;
+    let length = length.try_into().map_err(too_large)?;
+    if -1 == unsafe { libc::fallocate64(fd, libc::FALLOC_FL_KEEP_SIZE, offset, length) } {
+        let err = Error::last_os_error();
+        if err.raw_os_error() == Some(libc::EOPNOTSUPP) {
+            return Ok(());
+        }
+        return Err(err);
+    }
+    Ok(())
+}
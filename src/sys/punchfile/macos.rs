@@ -18,3 +18,27 @@ pub fn punchfile(file: &File, offset: u64, length: u64) -> io::Result<()> {
     }
     Ok(())
 }
+
+/// Allocates blocks for length bytes past the end of the file without changing the file size.
+/// offset must be the end of the file.
+pub fn preallocate(file: &File, _offset: u64, length: u64) -> io::Result<()> {
+    let length = length.try_into().map_err(|_| {
+        Error::new(
+            io::ErrorKind::InvalidInput,
+            "preallocation length too large",
+        )
+    })?;
+    let mut store = libc::fstore_t {
+        fst_flags: libc::F_ALLOCATEALL,
+        fst_posmode: libc::F_PEOFPOSMODE,
+        fst_offset: 0,
+        fst_length: length,
+        fst_bytesalloc: 0,
+    };
+    let fcntl_res =
+        unsafe { libc::fcntl(file.as_fd().as_raw_fd(), libc::F_PREALLOCATE, &mut store) };
+    if fcntl_res == -1 {
+        return Err(Error::last_os_error());
+    }
+    Ok(())
+}
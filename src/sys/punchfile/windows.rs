@@ -38,3 +38,9 @@ impl SparseFile for File {
         set_file_sparse(self, set_sparse)
     }
 }
+
+// TODO: SetFileInformationByHandle with FileAllocationInfo can reserve space without changing the
+// end of file.
+pub fn preallocate(_file: &File, _offset: u64, _length: u64) -> io::Result<()> {
+    Ok(())
+}
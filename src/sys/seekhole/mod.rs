@@ -69,6 +69,13 @@ impl<'a> Iter<'a> {
             file,
         }
     }
+    /// Iterates over the regions from offset to the end of the file.
+    pub fn starting_at(file: &'a mut File, offset: RegionOffset) -> Self {
+        Self {
+            offset,
+            ..Self::new(file)
+        }
+    }
 }
 
 impl Iterator for Iter<'_> {
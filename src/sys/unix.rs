@@ -7,3 +7,9 @@ pub fn path_disk_allocation(path: &Path) -> std::io::Result<u64> {
     use std::os::unix::fs::MetadataExt;
     Ok(metadata.blocks() * 512)
 }
+
+/// The number of hard links to file.
+pub fn file_link_count(file: &std::fs::File) -> std::io::Result<u64> {
+    use std::os::unix::fs::MetadataExt;
+    Ok(file.metadata()?.nlink())
+}
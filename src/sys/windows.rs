@@ -49,6 +49,13 @@ pub fn path_disk_allocation(path: &Path) -> io::Result<u64> {
     file_disk_allocation(&File::open(path)?)
 }
 
+/// The number of hard links to file.
+pub fn file_link_count(file: &File) -> io::Result<u64> {
+    let mut info = BY_HANDLE_FILE_INFORMATION::default();
+    unsafe { GetFileInformationByHandle(std_handle_to_windows(file.as_raw_handle()), &mut info) }?;
+    Ok(info.nNumberOfLinks.into())
+}
+
 // Do we need to require that I and O be slices? Does that mean we can do the bytes_returned element
 // calculations here rather than force the caller to do it?
 pub(crate) fn device_io_control<I: ?Sized, O: ?Sized>(
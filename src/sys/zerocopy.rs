@@ -0,0 +1,116 @@
+//! Copies that stay in the kernel, so value data doesn't pass through user space. Callers fall
+//! back to buffered copies when these are unsupported.
+
+use super::*;
+
+cfg_if! {
+    if #[cfg(target_os = "linux")] {
+        /// Copies up to len bytes from src at src_offset to dst at dst_offset, returning the number
+        /// copied. dst must not be opened for appending.
+        pub fn copy_file_range(
+            src: &File,
+            src_offset: u64,
+            dst: &File,
+            dst_offset: u64,
+            len: u64,
+        ) -> io::Result<u64> {
+            let mut off_in: libc::loff_t = src_offset.try_into().map_err(offset_error)?;
+            let mut off_out: libc::loff_t = dst_offset.try_into().map_err(offset_error)?;
+            let n = unsafe {
+                libc::copy_file_range(
+                    src.as_raw_fd(),
+                    &mut off_in,
+                    dst.as_raw_fd(),
+                    &mut off_out,
+                    len.try_into().unwrap_or(usize::MAX),
+                    0,
+                )
+            };
+            if n == -1 {
+                return Err(io::Error::last_os_error());
+            }
+            Ok(n as u64)
+        }
+
+        /// Sends up to len bytes from src at src_offset to dst, which can be any file, pipe or
+        /// socket. Returns the number of bytes sent.
+        pub fn sendfile(src: &File, src_offset: u64, dst: BorrowedFd, len: u64) -> io::Result<u64> {
+            let mut offset: libc::off_t = src_offset.try_into().map_err(offset_error)?;
+            let n = unsafe {
+                libc::sendfile(
+                    dst.as_raw_fd(),
+                    src.as_raw_fd(),
+                    &mut offset,
+                    len.try_into().unwrap_or(usize::MAX),
+                )
+            };
+            if n == -1 {
+                return Err(io::Error::last_os_error());
+            }
+            Ok(n as u64)
+        }
+
+        /// Moves up to len bytes from src at src_offset to dst, which must be a pipe. Returns the
+        /// number of bytes moved.
+        pub fn splice(src: &File, src_offset: u64, dst: BorrowedFd, len: u64) -> io::Result<u64> {
+            let mut off_in: libc::loff_t = src_offset.try_into().map_err(offset_error)?;
+            let n = unsafe {
+                libc::splice(
+                    src.as_raw_fd(),
+                    &mut off_in,
+                    dst.as_raw_fd(),
+                    std::ptr::null_mut(),
+                    len.try_into().unwrap_or(usize::MAX),
+                    libc::SPLICE_F_MOVE,
+                )
+            };
+            if n == -1 {
+                return Err(io::Error::last_os_error());
+            }
+            Ok(n as u64)
+        }
+
+        fn offset_error(_: std::num::TryFromIntError) -> io::Error {
+            io::Error::new(ErrorKind::InvalidInput, "offset out of range")
+        }
+    } else {
+        pub fn copy_file_range(
+            _src: &File,
+            _src_offset: u64,
+            _dst: &File,
+            _dst_offset: u64,
+            _len: u64,
+        ) -> io::Result<u64> {
+            Err(ErrorKind::Unsupported.into())
+        }
+
+        // macOS and FreeBSD sendfile only send to sockets.
+        #[cfg(unix)]
+        pub fn sendfile(_src: &File, _src_offset: u64, _dst: BorrowedFd, _len: u64) -> io::Result<u64> {
+            Err(ErrorKind::Unsupported.into())
+        }
+
+        // splice is Linux only.
+        #[cfg(unix)]
+        pub fn splice(_src: &File, _src_offset: u64, _dst: BorrowedFd, _len: u64) -> io::Result<u64> {
+            Err(ErrorKind::Unsupported.into())
+        }
+    }
+}
+
+/// Whether an error from a kernel copy means the copy should be done in user space instead.
+pub fn is_zero_copy_unsupported(err: &io::Error) -> bool {
+    if err.kind() == ErrorKind::Unsupported {
+        return true;
+    }
+    cfg_if! {
+        if #[cfg(unix)] {
+            matches!(
+                err.raw_os_error(),
+                Some(libc::EXDEV | libc::EINVAL | libc::ENOSYS | libc::EOPNOTSUPP)
+            )
+        } else {
+            false
+        }
+    }
+}
@@ -147,3 +147,125 @@ fn test_torrent_storage_benchmark() -> anyhow::Result<()> {
     use testing::torrent_storage::*;
     BENCHMARK_OPTS.build()?.run()
 }
+
+/// Show that a linked value is counted once, and isn't punched until its last key is removed.
+#[test]
+fn test_linked_values() -> Result<()> {
+    let tempdir = test_tempdir("test_linked_values")?;
+    let handle = Handle::new(tempdir.path.clone())?;
+    let block_size: usize = handle.block_size().try_into()?;
+    let value = readable_repeated_bytes(1, block_size);
+    handle.single_write_from("a".as_bytes().to_vec(), value.as_slice())?;
+    handle.link("a".as_bytes(), "b".as_bytes())?;
+    let mut batch = handle.new_writer()?;
+    batch.link("b".as_bytes().to_vec(), "c".as_bytes().to_vec());
+    batch.commit()?;
+    let sum_value_length = |handle: &Handle| -> Result<u64> {
+        Ok(handle
+            .start_deferred_transaction_for_read()?
+            .sum_value_length()?)
+    };
+    assert_eq!(sum_value_length(&handle)?, block_size as u64);
+    assert!(matches!(
+        handle.link("missing".as_bytes(), "d".as_bytes()),
+        Err(Error::NoSuchKey)
+    ));
+    handle.single_delete("a".as_bytes())?;
+    handle.delete_prefix("b".as_bytes())?;
+    assert_eq!(sum_value_length(&handle)?, block_size as u64);
+    let dir = handle.dir.clone();
+    let values_punched = handle.get_value_puncher_done();
+    drop(handle);
+    values_punched.wait();
+
+    let handle = Handle::new(tempdir.path.clone())?;
+    assert_repeated_bytes_values_eq(
+        handle.read_single("c".as_bytes())?.unwrap().new_reader(),
+        value.as_slice(),
+    );
+    handle.single_delete("c".as_bytes())?;
+    assert_eq!(sum_value_length(&handle)?, 0);
+    let values_punched = handle.get_value_puncher_done();
+    drop(handle);
+    values_punched.wait();
+    let mut allocated_space = 0;
+    for entry in dir.walk_dir()? {
+        if entry.entry_type != walk::EntryType::ValuesFile {
+            continue;
+        }
+        let mut file = File::open(&entry.path)?;
+        for region in seekhole::Iter::new(&mut file) {
+            let region = region?;
+            if matches!(region.region_type, seekhole::RegionType::Data) {
+                allocated_space += region.length();
+            }
+        }
+    }
+    assert_eq!(allocated_space, 0);
+    Ok(())
+}
+
+/// Show that large values lose their coldest extents to eviction, rather than being evicted whole.
+#[test]
+fn test_partial_eviction() -> Result<()> {
+    let tempdir = test_tempdir("test_partial_eviction")?;
+    let mut handle = Handle::new(tempdir.path.clone())?;
+    let block_size = handle.block_size();
+    handle.set_instance_limits(Limits {
+        max_value_length_sum: Some(4 * block_size),
+        partial_eviction_extent_size: Some(block_size),
+        ..Default::default()
+    })?;
+    let value = readable_repeated_bytes(1, (4 * block_size).try_into()?);
+    handle.single_write_from("big".as_bytes().to_vec(), value.as_slice())?;
+    // Timestamps have millisecond resolution.
+    std::thread::sleep(Duration::from_millis(2));
+    let mut reader = handle.read()?;
+    reader.add_range("big".as_bytes(), 0..block_size)?;
+    reader.begin()?;
+    std::thread::sleep(Duration::from_millis(2));
+    // A read in progress delays punching the evicted extent, but doesn't prevent it.
+    let reading_handle = Handle::new(tempdir.path.clone())?;
+    let held_reader = reading_handle.read()?;
+    let small = readable_repeated_bytes(2, block_size.try_into()?);
+    handle.single_write_from("small".as_bytes().to_vec(), small.as_slice())?;
+    let sum_value_length = handle
+        .start_deferred_transaction_for_read()?
+        .sum_value_length()?;
+    assert_eq!(sum_value_length, 4 * block_size);
+    let big = handle.read_single("big".as_bytes())?.unwrap();
+    assert_eq!(big.length(), 4 * block_size);
+    assert_eq!(big.missing_ranges().len(), 1);
+    let missing = big.missing_ranges()[0].clone();
+    assert_eq!(missing.end - missing.start, block_size);
+    assert!(big.is_range_present(0..block_size));
+    let mut present = vec![0; block_size.try_into()?];
+    big.new_reader().read_exact(&mut present)?;
+    assert_eq!(present, value[..present.len()]);
+    assert!(handle.read_single("small".as_bytes())?.is_some());
+    let Nonzero(location) = big.location else {
+        panic!("big value has no location");
+    };
+    // Give the value puncher time to try, and fail, to punch the evicted extent.
+    std::thread::sleep(2 * PUNCH_PENDING_TIMEOUT);
+    let mut values_file = File::open(file_path(handle.dir.path(), location.file_id))?;
+    assert!(check_hole(
+        &mut values_file,
+        location.file_offset + missing.start,
+        block_size
+    )
+    .is_err());
+    drop(held_reader);
+    drop(reading_handle);
+    // Snapshot values can hold locks on the regions they read from.
+    drop(big);
+    let values_punched = handle.get_value_puncher_done();
+    drop(handle);
+    values_punched.wait();
+    check_hole(
+        &mut values_file,
+        location.file_offset + missing.start,
+        block_size,
+    )?;
+    Ok(())
+}
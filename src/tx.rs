@@ -1,10 +1,17 @@
+use std::ops::Bound::{self, Excluded, Included, Unbounded};
+use std::ops::{Range, RangeBounds, RangeInclusive};
+
+use rusqlite::{params_from_iter, OptionalExtension};
+
 use super::*;
+use crate::item::PREFIX_SUMMARY_COLUMNS_SQL;
 
 /// This is more work to be done after the Handle conn mutex is released.
 #[must_use]
 pub(crate) struct PostCommitWork<'h, T> {
     handle: &'h Handle,
     deleted_values: Vec<NonzeroValueLocation>,
+    evicted_extents: Vec<NonzeroValueLocation>,
     altered_files: HashSet<FileId>,
     reward: T,
 }
@@ -98,34 +105,276 @@ pub trait ReadTransaction: ReadOnlyTransactionAccessor {
             .query_row(params![file_id, min_offset], |row| row.get(0))
     }
 
-    // TODO: Make this iterate.
+    /// Returns the generation of the value currently committed for key.
+    fn key_generation(&self, key: &[u8]) -> rusqlite::Result<Option<Generation>> {
+        match self
+            .readonly_transaction()
+            .prepare_cached_readonly("select generation from keys where key=?")?
+            .query_row([key], |row| row.get(0))
+        {
+            Err(QueryReturnedNoRows) => Ok(None),
+            default => default.map(Some),
+        }
+    }
+
+    /// Returns the value currently committed for key.
+    fn key_value(&self, key: &[u8]) -> rusqlite::Result<Option<Value>> {
+        match self
+            .readonly_transaction()
+            .prepare_cached_readonly(&format!(
+                "select {} from keys where key=?",
+                value_columns_sql()
+            ))?
+            .query_row([key], Value::from_row)
+        {
+            Err(QueryReturnedNoRows) => Ok(None),
+            default => default.map(Some),
+        }
+    }
+
+    /// Returns the missing ranges of the value at a location, in order.
+    fn missing_ranges(
+        &self,
+        file_id: &FileId,
+        file_offset: u64,
+    ) -> rusqlite::Result<Vec<Range<u64>>> {
+        self.readonly_transaction()
+            .prepare_cached_readonly(
+                "select range_offset, range_length from missing_ranges \
+                where file_id=? and file_offset=? order by range_offset",
+            )?
+            .query_map(params![file_id, file_offset], |row| {
+                let offset: u64 = row.get(0)?;
+                let length: u64 = row.get(1)?;
+                Ok(offset..offset + length)
+            })?
+            .collect()
+    }
+
+    /// Returns the values files referenced by any key.
+    fn referenced_file_ids(&self) -> rusqlite::Result<Vec<FileId>> {
+        self.readonly_transaction()
+            .prepare_cached_readonly("select distinct file_id from keys where file_id is not null")?
+            .query_map([], |row| row.get(0))?
+            .collect()
+    }
+
+    /// Returns the number of keys referencing the value at a location.
+    fn location_key_count(&self, file_id: &FileId, file_offset: u64) -> rusqlite::Result<u64> {
+        self.readonly_transaction()
+            .prepare_cached_readonly("select count(*) from keys where file_id=? and file_offset=?")?
+            .query_row(params![file_id, file_offset], |row| row.get(0))
+    }
+
+    /// Returns the committed changes after seq, oldest first. If the first change isn't seq + 1,
+    /// the changes in between were dropped from the change log.
+    fn changes_since(&self, seq: ChangeSeq) -> PubResult<Vec<Change>> {
+        self.readonly_transaction()
+            .prepare_cached_readonly(&format!(
+                "select {} from changes where seq > ? order by seq",
+                CHANGE_COLUMNS_SQL
+            ))?
+            .query_map([seq], Change::from_row)?
+            .collect::<rusqlite::Result<Vec<_>>>()
+            .map_err(Into::into)
+    }
+
+    /// Like changes_since, but only for keys with prefix, including renames from them.
+    fn changes_since_with_prefix(&self, seq: ChangeSeq, prefix: &[u8]) -> PubResult<Vec<Change>> {
+        // substr and length count bytes for blobs.
+        self.readonly_transaction()
+            .prepare_cached_readonly(&format!(
+                "select {} from changes where seq > ?1 \
+                and (substr(key, 1, length(?2)) = ?2 or substr(from_key, 1, length(?2)) = ?2) \
+                order by seq",
+                CHANGE_COLUMNS_SQL
+            ))?
+            .query_map(params![seq, prefix], Change::from_row)?
+            .collect::<rusqlite::Result<Vec<_>>>()
+            .map_err(Into::into)
+    }
+
+    /// The range of seqs in the change log, or None if it's empty.
+    fn change_log_seqs(&self) -> rusqlite::Result<Option<RangeInclusive<ChangeSeq>>> {
+        self.readonly_transaction()
+            .prepare_cached_readonly("select min(seq), max(seq) from changes")?
+            .query_row([], |row| {
+                Ok(Option::zip(row.get(0)?, row.get(1)?).map(|(min, max)| min..=max))
+            })
+    }
+
     fn list_items(&self, prefix: &[u8]) -> PubResult<Vec<Item>> {
-        let range_end = {
-            let mut prefix = prefix.to_owned();
-            if inc_big_endian_array(&mut prefix) {
-                Some(prefix)
+        self.list_items_page(&ListOptions {
+            prefix: prefix.to_vec(),
+            ..Default::default()
+        })
+    }
+
+    fn list_items_page(&self, options: &ListOptions) -> PubResult<Vec<Item>> {
+        let range_end = prefix_range_end(&options.prefix);
+        let mut filter = KeyFilter::prefix(&options.prefix, &range_end);
+        if let Some(start_after) = &options.start_after {
+            if options.reverse {
+                filter.end(Excluded(start_after));
             } else {
-                None
+                filter.start(Excluded(start_after));
             }
+        }
+        filter.list(self.readonly_transaction(), options.reverse, options.limit)
+    }
+
+    /// Lists the items with keys in range, in descending key order if reverse is set. Pass a pair
+    /// of Bounds for ranges over borrowed keys.
+    fn list_range(
+        &self,
+        range: impl RangeBounds<[u8]>,
+        reverse: bool,
+        limit: Option<usize>,
+    ) -> PubResult<Vec<Item>> {
+        KeyFilter::range(&range).list(self.readonly_transaction(), reverse, limit)
+    }
+
+    /// Summarizes the keys with prefix.
+    fn prefix_summary(&self, prefix: &[u8]) -> PubResult<PrefixSummary> {
+        let range_end = prefix_range_end(prefix);
+        let filter = KeyFilter::prefix(prefix, &range_end);
+        self.readonly_transaction()
+            .prepare_cached_readonly(&format!(
+                "select {} from keys {}",
+                PREFIX_SUMMARY_COLUMNS_SQL,
+                filter.where_clause()
+            ))?
+            .query_row(params_from_iter(&filter.params), |row| {
+                PrefixSummary::from_row(row, 0)
+            })
+            .map_err(Into::into)
+    }
+
+    /// Summarizes the keys with prefix, grouped by the key up to and including the first delimiter
+    /// after prefix. Keys without a delimiter after the prefix are their own group. Groups are
+    /// returned in key order. The delimiter can't be empty.
+    fn prefix_summary_grouped(
+        &self,
+        prefix: &[u8],
+        delimiter: &[u8],
+    ) -> PubResult<Vec<(Vec<u8>, PrefixSummary)>> {
+        if delimiter.is_empty() {
+            return Err(io::Error::new(InvalidInput, "delimiter is empty").into());
+        }
+        let range_end = prefix_range_end(prefix);
+        let filter = KeyFilter::prefix(prefix, &range_end);
+        let prefix_len = prefix.len() as i64;
+        let mut params: Vec<&dyn ToSql> = vec![&prefix_len, &delimiter];
+        params.extend(filter.params.iter().map(|key| key as &dyn ToSql));
+        // instr, substr and length all count bytes for blobs.
+        self.readonly_transaction()
+            .prepare_cached_readonly(&format!(
+                "select \
+                    case when instr(substr(key, ?1 + 1), ?2) > 0 \
+                    then substr(key, 1, ?1 + instr(substr(key, ?1 + 1), ?2) + length(?2) - 1) \
+                    else key end as grp, \
+                    {} \
+                from keys {} group by grp order by grp",
+                PREFIX_SUMMARY_COLUMNS_SQL,
+                filter.where_clause()
+            ))?
+            .query_map(&params[..], |row| {
+                Ok((row.get(0)?, PrefixSummary::from_row(row, 1)?))
+            })?
+            .collect::<rusqlite::Result<Vec<_>>>()
+            .map_err(Into::into)
+    }
+
+    /// Counts the keys in range.
+    fn count_range(&self, range: impl RangeBounds<[u8]>) -> PubResult<u64> {
+        let filter = KeyFilter::range(&range);
+        self.readonly_transaction()
+            .prepare_cached_readonly(&format!(
+                "select count(*) from keys {}",
+                filter.where_clause()
+            ))?
+            .query_row(params_from_iter(&filter.params), |row| row.get(0))
+            .map_err(Into::into)
+    }
+}
+
+/// Builds the where clause for queries over ranges of keys.
+#[derive(Default)]
+struct KeyFilter<'a> {
+    conditions: Vec<&'static str>,
+    params: Vec<&'a [u8]>,
+}
+
+impl<'a> KeyFilter<'a> {
+    fn range(range: &'a impl RangeBounds<[u8]>) -> Self {
+        let mut filter = Self::default();
+        filter.start(range.start_bound());
+        filter.end(range.end_bound());
+        filter
+    }
+
+    fn prefix(prefix: &'a [u8], range_end: &'a Option<Vec<u8>>) -> Self {
+        let mut filter = Self::default();
+        filter.start(Included(prefix));
+        filter.end(match range_end {
+            Some(range_end) => Excluded(range_end),
+            None => Unbounded,
+        });
+        filter
+    }
+
+    fn start(&mut self, bound: Bound<&'a (impl AsRef<[u8]> + ?Sized)>) {
+        self.bound(bound, "key >= ?", "key > ?")
+    }
+
+    fn end(&mut self, bound: Bound<&'a (impl AsRef<[u8]> + ?Sized)>) {
+        self.bound(bound, "key <= ?", "key < ?")
+    }
+
+    fn bound(
+        &mut self,
+        bound: Bound<&'a (impl AsRef<[u8]> + ?Sized)>,
+        included: &'static str,
+        excluded: &'static str,
+    ) {
+        let (condition, key) = match bound {
+            Included(key) => (included, key),
+            Excluded(key) => (excluded, key),
+            Unbounded => return,
         };
-        match range_end {
-            None => list_items_inner(
-                self.readonly_transaction(),
-                &format!(
-                    "select {}, key from keys where key >= ?",
-                    value_columns_sql()
-                ),
-                [prefix],
-            ),
-            Some(range_end) => list_items_inner(
-                self.readonly_transaction(),
-                &format!(
-                    "select {}, key from keys where key >= ? and key < ?",
-                    value_columns_sql()
-                ),
-                rusqlite::params![prefix, range_end],
-            ),
+        self.conditions.push(condition);
+        self.params.push(key.as_ref());
+    }
+
+    fn where_clause(&self) -> String {
+        if self.conditions.is_empty() {
+            return String::new();
         }
+        format!("where {}", self.conditions.join(" and "))
+    }
+
+    fn list(
+        &self,
+        tx: &rusqlite::Transaction,
+        reverse: bool,
+        limit: Option<usize>,
+    ) -> PubResult<Vec<Item>> {
+        // A negative limit means no limit in sqlite.
+        let limit: i64 = limit
+            .map(|limit| limit.try_into().unwrap_or(i64::MAX))
+            .unwrap_or(-1);
+        let mut params: Vec<&dyn ToSql> = self.params.iter().map(|key| key as &dyn ToSql).collect();
+        params.push(&limit);
+        list_items_inner(
+            tx,
+            &format!(
+                "select {}, key from keys {} order by key {} limit ?",
+                value_columns_sql(),
+                self.where_clause(),
+                if reverse { "desc" } else { "asc" }
+            ),
+            &params[..],
+        )
     }
 }
 
@@ -136,12 +385,7 @@ fn list_items_inner(
 ) -> PubResult<Vec<Item>> {
     tx.prepare_cached_readonly(sql)
         .unwrap()
-        .query_map(params, |row| {
-            Ok(Item {
-                value: Value::from_row(row)?,
-                key: row.get(VALUE_COLUMN_NAMES.len())?,
-            })
-        })?
+        .query_map(params, Item::from_row)?
         .collect::<rusqlite::Result<Vec<_>>>()
         .map_err(Into::into)
 }
@@ -152,6 +396,7 @@ impl<'h, T> PostCommitWork<'h, T> {
         // punches to not persist. It doesn't fix the problem, but it significantly reduces it.
         if !self.handle.instance_limits.disable_hole_punching {
             self.handle.send_values_for_delete(self.deleted_values);
+            self.handle.punch_extents(self.evicted_extents);
         }
         // Forget any references to clones of files that have changed.
         for file_id in self.altered_files {
@@ -167,6 +412,8 @@ pub(crate) struct Transaction<'h> {
     tx: rusqlite::Transaction<'h>,
     handle: &'h Handle,
     deleted_values: Vec<NonzeroValueLocation>,
+    // Block aligned file regions of partially evicted values.
+    evicted_extents: Vec<NonzeroValueLocation>,
     altered_files: HashSet<FileId>,
 }
 
@@ -186,101 +433,226 @@ impl<'h> Transaction<'h> {
             tx,
             handle,
             deleted_values: vec![],
+            evicted_extents: vec![],
             altered_files: Default::default(),
         }
     }
 
     pub(crate) fn commit<T>(mut self, reward: T) -> Result<PostCommitWork<'h, T>> {
+        self.retain_unreferenced_deleted_values()?;
         self.apply_limits()?;
         self.tx.commit()?;
         Ok(PostCommitWork {
             handle: self.handle,
             deleted_values: self.deleted_values,
+            evicted_extents: self.evicted_extents,
             altered_files: self.altered_files,
             reward,
         })
     }
 
-    pub fn touch_for_read(&mut self, key: &[u8]) -> rusqlite::Result<Value> {
-        self.tx
+    /// Updates last_used for key if it still has the given generation, and for the extents of its
+    /// value that overlap range, or all of them if range is None.
+    pub fn touch_range_for_read(
+        &mut self,
+        key: &[u8],
+        generation: Generation,
+        range: Option<Range<u64>>,
+    ) -> rusqlite::Result<Option<Value>> {
+        let Some(value) = self
+            .tx
             .prepare_cached(&format!(
                 "update keys \
                 set last_used=cast(unixepoch('subsec')*1e3 as integer) \
-                where key=? \
+                where key=? and generation=? \
                 returning {}",
                 value_columns_sql()
             ))?
-            .query_row([key], Value::from_row)
+            .query_row(params![key, generation], Value::from_row)
+            .optional()?
+        else {
+            return Ok(None);
+        };
+        if let Nonzero(location) = value.location {
+            let range = range.unwrap_or(0..location.length);
+            self.tx
+                .prepare_cached(
+                    "update value_extents \
+                    set last_used=cast(unixepoch('subsec')*1e3 as integer) \
+                    where file_id=? and file_offset=? \
+                    and extent_offset < ? and extent_offset + extent_length > ?",
+                )?
+                .execute(params![
+                    location.file_id,
+                    location.file_offset,
+                    range.end,
+                    range.start
+                ])?;
+        }
+        Ok(Some(value))
     }
 
     // TODO: Add a test for renaming onto itself.
+    /// Moves a key referencing value to new_key. If the value is linked, one of its keys is chosen.
     pub fn rename_value(&mut self, value: &Value, new_key: Vec<u8>) -> PubResult<bool> {
-        match self
+        let existing = self
             .tx
             .prepare_cached(&format!(
-                "delete from keys where key=? returning {}",
+                "select {} from keys where key=?",
                 value_columns_sql()
             ))?
-            .query_row(params![&new_key], Value::from_row)
-        {
+            .query_row(params![&new_key], Value::from_row);
+        match existing {
             Err(QueryReturnedNoRows) => {}
             Err(err) => return Err(err.into()),
             Ok(existing_value) => {
-                match existing_value.location {
-                    Nonzero(a) => {
-                        let b = value;
-                        if Some(a.file_offset) == b.file_offset() && Some(&a.file_id) == b.file_id()
-                        {
-                            assert_eq!(a.length, b.length());
-                            // Renamed but the name is the same.
-                            return Ok(true);
-                        }
-                        // Schedule the value that previously had the key to be hole punched.
-                        self.deleted_values.push(a);
+                if let Nonzero(a) = existing_value.location {
+                    let b = value;
+                    if Some(a.file_offset) == b.file_offset() && Some(&a.file_id) == b.file_id() {
+                        assert_eq!(a.length, b.length());
+                        // Renamed but the name is the same.
+                        return Ok(true);
                     }
-                    ZeroLength => {}
                 }
+                // This schedules the value that previously had the key to be hole punched.
+                self.remove_key(&new_key)?;
             }
         };
 
-        let res: rusqlite::Result<ValueLength> = self
+        let res: rusqlite::Result<Vec<u8>> = self
             .tx
-            .prepare_cached(
-                "update keys set key=? where file_id=? and file_offset=?\
-                returning value_length",
-            )?
-            .query_row(
-                params![new_key, value.file_id(), value.file_offset()],
-                |row| row.get(0),
-            );
-        match res {
-            Err(QueryReturnedNoRows) => Ok(false),
-            Err(err) => Err(err).context("updating value key").map_err(Into::into),
-            Ok(value_length) => {
-                assert_eq!(value_length, value.length());
-                Ok(true)
-            }
-        }
+            .prepare_cached("select key from keys where file_id=? and file_offset=? limit 1")?
+            .query_row(params![value.file_id(), value.file_offset()], |row| {
+                row.get(0)
+            });
+        let from_key = match res {
+            Err(QueryReturnedNoRows) => return Ok(false),
+            Err(err) => return Err(err).context("finding value key").map_err(Into::into),
+            Ok(from_key) => from_key,
+        };
+        let renamed = self
+            .tx
+            .prepare_cached(&format!(
+                "update keys set key=? where key=? returning {}",
+                value_columns_sql()
+            ))?
+            .query_row(params![new_key, from_key], Value::from_row)
+            .context("updating value key")?;
+        assert_eq!(renamed.length(), value.length());
+        self.record_change(ChangeOp::Rename, &new_key, Some(&from_key), &renamed)?;
+        Ok(true)
     }
 
     // I guess this doesn't handle destination collisions? It should give a unique constraint error
     // from sqlite.
     pub fn rename_item(&mut self, from: &[u8], to: &[u8]) -> PubResult<Timestamp> {
-        let row_result = self.tx.query_row(
-            "update keys set key=? where key=? returning last_used",
-            [to, from],
-            |row| {
-                let ts: Timestamp = row.get(0)?;
-                Ok(ts)
-            },
-        );
-        let last_used = match row_result {
+        let row_result = self
+            .tx
+            .prepare_cached(&format!(
+                "update keys set key=? where key=? returning {}",
+                value_columns_sql()
+            ))?
+            .query_row([to, from], Value::from_row);
+        let value = match row_result {
             Err(QueryReturnedNoRows) => Err(Error::NoSuchKey),
             Ok(ok) => Ok(ok),
             Err(err) => Err(err.into()),
         }?;
         assert_eq!(self.tx.changes(), 1);
-        Ok(last_used)
+        self.record_change(ChangeOp::Rename, to, Some(from), &value)?;
+        Ok(value.last_used())
+    }
+
+    /// Renames all keys with prefix from to have prefix to instead, in a single statement.
+    pub fn rename_prefix(&mut self, from: &[u8], to: &[u8]) -> PubResult<BulkOpStats> {
+        let range_end = prefix_range_end(from);
+        let filter = KeyFilter::prefix(from, &range_end);
+        let suffix_start = from.len() as i64 + 1;
+        let mut params: Vec<&dyn ToSql> = vec![&to, &suffix_start];
+        params.extend(filter.params.iter().map(|key| key as &dyn ToSql));
+        let items = self
+            .tx
+            .prepare_cached(&format!(
+                "update keys set key=unhex(hex(?1) || hex(substr(key, ?2))) {} returning {}, key",
+                filter.where_clause(),
+                value_columns_sql()
+            ))?
+            .query_map(&params[..], Item::from_row)?
+            .collect::<rusqlite::Result<Vec<_>>>()?;
+        let mut stats = BulkOpStats::default();
+        let mut from_key = from.to_vec();
+        for Item { key, value } in items {
+            from_key.truncate(from.len());
+            from_key.extend_from_slice(&key[to.len()..]);
+            self.record_change(ChangeOp::Rename, &key, Some(&from_key), &value)?;
+            stats.add(&value);
+        }
+        Ok(stats)
+    }
+
+    /// Appends to the change log.
+    fn record_change(
+        &mut self,
+        op: ChangeOp,
+        key: &[u8],
+        from_key: Option<&[u8]>,
+        value: &Value,
+    ) -> rusqlite::Result<()> {
+        self.tx
+            .prepare_cached(
+                "insert into changes (op, key, from_key, value_length, last_used) \
+                values (?, ?, ?, ?, ?)",
+            )?
+            .execute(params![
+                op,
+                key,
+                from_key,
+                value.length(),
+                value.last_used()
+            ])?;
+        Ok(())
+    }
+
+    /// Claims the fetch of key, taking over any existing claim older than timeout. Returns false if
+    /// someone else holds the claim.
+    pub(crate) fn try_claim_fetch(
+        &mut self,
+        key: &[u8],
+        claim_id: FetchClaimId,
+        timeout: Duration,
+    ) -> rusqlite::Result<bool> {
+        let timeout_millis = i64::try_from(timeout.as_millis()).unwrap_or(i64::MAX);
+        self.tx
+            .prepare_cached(
+                "delete from fetch_claims \
+                where key=? and claimed_at <= cast(unixepoch('subsec')*1e3 as integer) - ?",
+            )?
+            .execute(params![key, timeout_millis])?;
+        let inserted = self
+            .tx
+            .prepare_cached(
+                "insert into fetch_claims (key, claim_id) values (?, ?) on conflict do nothing",
+            )?
+            .execute(params![key, claim_id])?;
+        Ok(inserted == 1)
+    }
+
+    /// Removes a fetch claim, if it's still ours.
+    pub(crate) fn release_fetch_claim(
+        &mut self,
+        key: &[u8],
+        claim_id: FetchClaimId,
+    ) -> rusqlite::Result<()> {
+        self.tx
+            .prepare_cached("delete from fetch_claims where key=? and claim_id=?")?
+            .execute(params![key, claim_id])?;
+        Ok(())
+    }
+
+    fn next_generation(&mut self) -> rusqlite::Result<Generation> {
+        self.tx
+            .prepare_cached("update sums set value=value+1 where key='generation' returning value")?
+            .query_row([], |row| row.get(0))
     }
 
     pub(crate) fn insert_key(&mut self, pw: PendingWrite) -> rusqlite::Result<()> {
@@ -290,33 +662,176 @@ impl<'h> Transaction<'h> {
             file_id = None;
             file_offset = None;
         }
+        let generation = self.next_generation()?;
         let inserted = self
             .tx
+            .prepare_cached(&format!(
+                "insert into keys (key, file_id, file_offset, value_length, generation, last_used)\
+                values (?, ?, ?, ?, ?, coalesce(?, cast(unixepoch('subsec')*1e3 as integer)))\
+                returning {}",
+                value_columns_sql()
+            ))?
+            .query_row(
+                rusqlite::params!(
+                    pw.key,
+                    file_id,
+                    file_offset,
+                    pw.value_length,
+                    generation,
+                    pw.last_used
+                ),
+                Value::from_row,
+            )?;
+        self.record_change(ChangeOp::Write, &pw.key, None, &inserted)?;
+        if let Nonzero(location) = inserted.location {
+            self.insert_extents(&location, 0..location.length)?;
+            for range in pw.missing_ranges {
+                self.insert_missing_range(location.file_id, location.file_offset, range)?;
+            }
+        }
+        if pw.value_length != 0 {
+            self.altered_files.insert(pw.value_file_id);
+        }
+        Ok(())
+    }
+
+    /// Makes new_key reference the same stored value as existing_key, replacing any value new_key
+    /// had.
+    pub fn link_key(&mut self, existing_key: &[u8], new_key: &[u8]) -> PubResult<Value> {
+        let res = self
+            .tx
+            .prepare_cached(&format!(
+                "select {} from keys where key=?",
+                value_columns_sql()
+            ))?
+            .query_row([existing_key], Value::from_row);
+        let existing = match res {
+            Err(QueryReturnedNoRows) => return Err(Error::NoSuchKey),
+            Err(err) => return Err(err.into()),
+            Ok(value) => value,
+        };
+        if existing_key == new_key {
+            return Ok(existing);
+        }
+        self.remove_key(new_key)?;
+        let generation = self.next_generation()?;
+        let linked = self
+            .tx
+            .prepare_cached(&format!(
+                "insert into keys (key, file_id, file_offset, value_length, generation) \
+                values (?, ?, ?, ?, ?) \
+                returning {}",
+                value_columns_sql()
+            ))?
+            .query_row(
+                params![
+                    new_key,
+                    existing.file_id(),
+                    existing.file_offset(),
+                    existing.length(),
+                    generation
+                ],
+                Value::from_row,
+            )?;
+        self.record_change(ChangeOp::Write, new_key, None, &linked)?;
+        Ok(linked)
+    }
+
+    /// Schedules a removed value to be punched. Values that are still referenced at commit, by
+    /// linked keys or a value appended in place, are left alone.
+    fn push_value_for_deletion(&mut self, value: Value) {
+        match value.location {
+            Nonzero(location) if !self.deleted_values.contains(&location) => {
+                self.deleted_values.push(location)
+            }
+            _ => {}
+        }
+    }
+
+    fn insert_missing_range(
+        &mut self,
+        file_id: FileId,
+        file_offset: u64,
+        range: Range<u64>,
+    ) -> rusqlite::Result<()> {
+        self.tx
             .prepare_cached(
-                "insert into keys (key, file_id, file_offset, value_length)\
+                "insert into missing_ranges (file_id, file_offset, range_offset, range_length) \
                 values (?, ?, ?, ?)",
             )?
-            .execute(rusqlite::params!(
-                pw.key,
+            .execute(params![
                 file_id,
                 file_offset,
-                pw.value_length
-            ))?;
-        assert_eq!(inserted, 1);
-        if pw.value_length != 0 {
-            self.altered_files.insert(pw.value_file_id);
+                range.start,
+                range.end - range.start
+            ])?;
+        Ok(())
+    }
+
+    /// Marks a range of the value at location as present.
+    pub(crate) fn fill_missing_range(
+        &mut self,
+        location: &NonzeroValueLocation,
+        offset: u64,
+        length: u64,
+    ) -> rusqlite::Result<()> {
+        let filled = offset..offset + length;
+        let overlapping: Vec<Range<u64>> = self
+            .missing_ranges(&location.file_id, location.file_offset)?
+            .into_iter()
+            .filter(|missing| missing.start < filled.end && filled.start < missing.end)
+            .collect();
+        self.insert_extents(location, filled.clone())?;
+        for missing in overlapping {
+            self.tx
+                .prepare_cached(
+                    "delete from missing_ranges \
+                    where file_id=? and file_offset=? and range_offset=?",
+                )?
+                .execute(params![
+                    location.file_id,
+                    location.file_offset,
+                    missing.start
+                ])?;
+            for remainder in [missing.start..filled.start, filled.end..missing.end] {
+                if !remainder.is_empty() {
+                    self.insert_missing_range(location.file_id, location.file_offset, remainder)?;
+                }
+            }
         }
         Ok(())
     }
 
-    fn push_value_for_deletion(&mut self, value: Value) {
-        match value.location {
-            Nonzero(location) => self.deleted_values.push(location),
-            ZeroLength => {}
+    fn retain_unreferenced_deleted_values(&mut self) -> rusqlite::Result<()> {
+        let mut deleted_values = std::mem::take(&mut self.deleted_values);
+        let mut retain_err = Ok(());
+        deleted_values.retain(|location| {
+            match self.location_key_count(&location.file_id, location.file_offset) {
+                Ok(count) => count == 0,
+                Err(err) => {
+                    retain_err = Err(err);
+                    true
+                }
+            }
+        });
+        retain_err?;
+        // Range and extent tracking goes with the value.
+        for location in &deleted_values {
+            for sql in [
+                "delete from missing_ranges where file_id=? and file_offset=?",
+                "delete from value_extents where file_id=? and file_offset=?",
+            ] {
+                self.tx
+                    .prepare_cached(sql)?
+                    .execute(params![location.file_id, location.file_offset])?;
+            }
         }
+        self.deleted_values = deleted_values;
+        Ok(())
     }
 
-    pub fn delete_key(&mut self, key: &[u8]) -> rusqlite::Result<Option<c_api::PossumStat>> {
+    /// Removes key without recording a change, because it's about to be replaced.
+    pub(crate) fn remove_key(&mut self, key: &[u8]) -> rusqlite::Result<Option<Value>> {
         let res = self
             .tx
             .prepare_cached(&format!(
@@ -327,14 +842,54 @@ impl<'h> Transaction<'h> {
         match res {
             Err(QueryReturnedNoRows) => Ok(None),
             Ok(value) => {
-                let stat = value.as_ref().into();
                 self.push_value_for_deletion(value);
-                Ok(Some(stat))
+                Ok(Some(value))
             }
             Err(err) => Err(err),
         }
     }
 
+    pub fn delete_key(&mut self, key: &[u8]) -> rusqlite::Result<Option<c_api::PossumStat>> {
+        let Some(value) = self.remove_key(key)? else {
+            return Ok(None);
+        };
+        self.record_change(ChangeOp::Delete, key, None, &value)?;
+        Ok(Some(value.as_ref().into()))
+    }
+
+    /// Deletes keys with prefix in a single statement, at most limit of them if given, in key
+    /// order.
+    pub fn delete_prefix(&mut self, prefix: &[u8], limit: Option<usize>) -> PubResult<BulkOpStats> {
+        let range_end = prefix_range_end(prefix);
+        let filter = KeyFilter::prefix(prefix, &range_end);
+        // A negative limit means no limit in sqlite.
+        let limit: i64 = limit
+            .map(|limit| limit.try_into().unwrap_or(i64::MAX))
+            .unwrap_or(-1);
+        let mut params: Vec<&dyn ToSql> =
+            filter.params.iter().map(|key| key as &dyn ToSql).collect();
+        params.push(&limit);
+        let items = self
+            .tx
+            .prepare_cached(&format!(
+                "delete from keys where key_id in (\
+                    select key_id from keys {} order by key limit ?\
+                ) \
+                returning {}, key",
+                filter.where_clause(),
+                value_columns_sql()
+            ))?
+            .query_map(&params[..], Item::from_row)?
+            .collect::<rusqlite::Result<Vec<_>>>()?;
+        let mut stats = BulkOpStats::default();
+        for Item { key, value } in items {
+            self.record_change(ChangeOp::Delete, &key, None, &value)?;
+            stats.add(&value);
+            self.push_value_for_deletion(value);
+        }
+        Ok(stats)
+    }
+
     pub fn apply_limits(&mut self) -> Result<()> {
         if self.tx.transaction_state(None)? != rusqlite::TransactionState::Write {
             return Ok(());
@@ -348,31 +903,195 @@ impl<'h> Transaction<'h> {
                     break;
                 }
                 self.evict_values(actual - max)?;
+                // Missing ranges of evicted values are only dropped here.
+                self.retain_unreferenced_deleted_values()?;
             }
         }
+        let max_change_log_len = self
+            .handle
+            .instance_limits
+            .max_change_log_len
+            .unwrap_or(DEFAULT_MAX_CHANGE_LOG_LEN);
+        self.tx
+            .prepare_cached("delete from changes where seq <= (select max(seq) from changes) - ?")?
+            .execute([max_change_log_len])
+            .context("trimming change log")?;
         Ok(())
     }
 
     pub fn evict_values(&mut self, target_bytes: u64) -> Result<()> {
-        let mut stmt = self.tx.prepare_cached(&format!(
-            "delete from keys where key_id in (\
-                select key_id from keys order by last_used limit 1\
-            )\
-            returning {}",
-            value_columns_sql()
-        ))?;
         let mut value_bytes_deleted = 0;
-        let mut values_deleted = vec![];
         while value_bytes_deleted < target_bytes {
-            let value = stmt.query_row([], Value::from_row)?;
-            value_bytes_deleted += value.length();
-            info!("evicting {:?}", &value);
-            values_deleted.push(value);
+            let coldest_extent = self.coldest_extent()?;
+            value_bytes_deleted += match coldest_extent {
+                Some(extent)
+                    if self
+                        .coldest_untracked_last_used()?
+                        .is_none_or(|last_used| extent.last_used <= last_used) =>
+                {
+                    self.evict_extent(extent)?
+                }
+                _ => self.evict_coldest_value()?,
+            };
         }
-        drop(stmt);
-        for value in values_deleted {
-            self.push_value_for_deletion(value);
+        Ok(())
+    }
+
+    /// Evicts the least recently used value that isn't tracked by extent.
+    fn evict_coldest_value(&mut self) -> Result<u64> {
+        let item = self
+            .tx
+            .prepare_cached(&format!(
+                "delete from keys where key_id in (\
+                    select key_id from keys where not exists (\
+                        select 1 from value_extents e \
+                        where e.file_id=keys.file_id and e.file_offset=keys.file_offset\
+                    ) order by last_used limit 1\
+                )\
+                returning {}, key",
+                value_columns_sql()
+            ))?
+            .query_row([], Item::from_row)?;
+        info!("evicting {:?}", &item.value);
+        let Item { key, value } = item;
+        self.record_change(ChangeOp::Evict, &key, None, &value)?;
+        self.push_value_for_deletion(value);
+        Ok(value.length())
+    }
+
+    fn coldest_untracked_last_used(&self) -> rusqlite::Result<Option<Timestamp>> {
+        self.tx
+            .prepare_cached(
+                "select min(last_used) from keys where not exists (\
+                    select 1 from value_extents e \
+                    where e.file_id=keys.file_id and e.file_offset=keys.file_offset)",
+            )?
+            .query_row([], |row| row.get(0))
+    }
+
+    fn coldest_extent(&self) -> rusqlite::Result<Option<ColdExtent>> {
+        self.tx
+            .prepare_cached(
+                "select file_id, file_offset, extent_offset, extent_length, last_used \
+                from value_extents order by last_used limit 1",
+            )?
+            .query_row([], |row| {
+                let offset: u64 = row.get(2)?;
+                let length: u64 = row.get(3)?;
+                Ok(ColdExtent {
+                    file_id: row.get(0)?,
+                    file_offset: row.get(1)?,
+                    range: offset..offset + length,
+                    last_used: row.get(4)?,
+                })
+            })
+            .optional()
+    }
+
+    /// Marks an extent missing and schedules its blocks to be punched. The last extent of a value
+    /// evicts the whole value instead. Returns the number of present bytes evicted.
+    fn evict_extent(&mut self, extent: ColdExtent) -> Result<u64> {
+        let ColdExtent {
+            file_id,
+            file_offset,
+            range,
+            ..
+        } = extent;
+        let extent_count: u64 = self
+            .tx
+            .prepare_cached("select count(*) from value_extents where file_id=? and file_offset=?")?
+            .query_row(params![file_id, file_offset], |row| row.get(0))?;
+        self.tx
+            .prepare_cached(
+                "delete from value_extents where file_id=? and file_offset=? and extent_offset=?",
+            )?
+            .execute(params![file_id, file_offset, range.start])?;
+        if extent_count == 1 {
+            let items = self
+                .tx
+                .prepare_cached(&format!(
+                    "delete from keys where file_id=? and file_offset=? returning {}, key",
+                    value_columns_sql()
+                ))?
+                .query_map(params![file_id, file_offset], Item::from_row)?
+                .collect::<rusqlite::Result<Vec<_>>>()?;
+            let mut value_bytes_deleted = 0;
+            for Item { key, value } in items {
+                info!("evicting {:?}", &value);
+                self.record_change(ChangeOp::Evict, &key, None, &value)?;
+                value_bytes_deleted = value.length();
+                self.push_value_for_deletion(value);
+            }
+            return Ok(value_bytes_deleted);
+        }
+        let missing = self.missing_ranges(&file_id, file_offset)?;
+        let mut value_bytes_deleted = 0;
+        for present in subtract_ranges(range.clone(), &missing) {
+            value_bytes_deleted += present.end - present.start;
+            self.insert_missing_range(file_id, file_offset, present)?;
+        }
+        let block_size = self.handle.block_size();
+        let punch_start = ceil_multiple(file_offset + range.start, block_size);
+        let punch_end = floored_multiple(file_offset + range.end, block_size);
+        if punch_end > punch_start {
+            info!(%file_id, %file_offset, ?range, "evicting extent");
+            self.evicted_extents.push(NonzeroValueLocation {
+                file_id,
+                file_offset: punch_start,
+                length: punch_end - punch_start,
+            });
+        }
+        Ok(value_bytes_deleted)
+    }
+
+    fn extent_size(&self) -> Option<u64> {
+        let block_size = self.handle.block_size();
+        self.handle
+            .instance_limits
+            .partial_eviction_extent_size
+            .map(|size| ceil_multiple(max(size, 1), block_size))
+    }
+
+    /// Starts tracking usage of the extents of the value at location that overlap range, if
+    /// partial eviction is enabled and the value is large enough. Extent boundaries are multiples
+    /// of the extent size in the values file, so they can be punched without disturbing
+    /// neighbouring values.
+    fn insert_extents(
+        &mut self,
+        location: &NonzeroValueLocation,
+        range: Range<u64>,
+    ) -> rusqlite::Result<()> {
+        let Some(extent_size) = self.extent_size() else {
+            return Ok(());
+        };
+        if location.length <= extent_size {
+            return Ok(());
+        }
+        let value_end = location.file_offset + location.length;
+        let mut start = floored_multiple(location.file_offset + range.start, extent_size);
+        while start < location.file_offset + range.end {
+            let extent_start = max(start, location.file_offset);
+            let extent_end = min(start + extent_size, value_end);
+            self.tx
+                .prepare_cached(
+                    "insert or ignore into value_extents \
+                    (file_id, file_offset, extent_offset, extent_length) values (?, ?, ?, ?)",
+                )?
+                .execute(params![
+                    location.file_id,
+                    location.file_offset,
+                    extent_start - location.file_offset,
+                    extent_end - extent_start
+                ])?;
+            start += extent_size;
         }
         Ok(())
     }
 }
+
+struct ColdExtent {
+    file_id: FileId,
+    file_offset: u64,
+    range: Range<u64>,
+    last_used: Timestamp,
+}
@@ -0,0 +1,88 @@
+use rusqlite::{OpenFlags, TransactionBehavior};
+
+use super::*;
+
+/// How often a Watch checks the manifest for commits.
+const WATCH_POLL_INTERVAL: Duration = Duration::from_millis(10);
+
+/// Subscription to changes committed by any process for keys with a prefix. Commits are detected
+/// with the manifest's data_version pragma on a dedicated connection, and the matching changes are
+/// read from the change log.
+pub struct Watch {
+    conn: Connection,
+    prefix: Vec<u8>,
+    last_seq: ChangeSeq,
+    data_version: i64,
+}
+
+impl Watch {
+    pub(crate) fn new(dir: &Path, prefix: Vec<u8>) -> PubResult<Self> {
+        let conn = Connection::open_with_flags(
+            dir.join(MANIFEST_DB_FILE_NAME),
+            OpenFlags::SQLITE_OPEN_READ_ONLY
+                | OpenFlags::SQLITE_OPEN_NO_MUTEX
+                | OpenFlags::SQLITE_OPEN_URI,
+        )?;
+        // Get the data version first, so a commit that lands before we read the last seq is picked
+        // up by the next poll.
+        let data_version = Self::query_data_version(&conn)?;
+        let last_seq = conn.query_row("select coalesce(max(seq), 0) from changes", [], |row| {
+            row.get(0)
+        })?;
+        Ok(Self {
+            conn,
+            prefix,
+            last_seq,
+            data_version,
+        })
+    }
+
+    fn query_data_version(conn: &Connection) -> rusqlite::Result<i64> {
+        conn.pragma_query_value(None, "data_version", |row| row.get(0))
+    }
+
+    /// Returns the matching changes committed since the last call, without blocking. Renames are
+    /// included if either the new or previous key matches. If changes were trimmed from the change
+    /// log before they were seen, Error::ChangesDropped is returned, and the watch continues from
+    /// the latest change. Callers should then resynchronize by listing the prefix.
+    pub fn poll(&mut self) -> PubResult<Vec<Change>> {
+        let data_version = Self::query_data_version(&self.conn)?;
+        if data_version == self.data_version {
+            return Ok(vec![]);
+        }
+        self.data_version = data_version;
+        let tx = ReadTransactionOwned(
+            self.conn
+                .transaction_with_behavior(TransactionBehavior::Deferred)?,
+        );
+        let Some(seqs) = tx.change_log_seqs()? else {
+            return Ok(vec![]);
+        };
+        let last_seq = std::mem::replace(&mut self.last_seq, *seqs.end());
+        if *seqs.start() > last_seq + 1 {
+            return Err(Error::ChangesDropped);
+        }
+        tx.changes_since_with_prefix(last_seq, &self.prefix)
+    }
+
+    /// Blocks until matching changes are committed, or the timeout elapses, in which case the
+    /// result is empty. A timeout of None waits indefinitely.
+    pub fn wait(&mut self, timeout: Option<Duration>) -> PubResult<Vec<Change>> {
+        let deadline = timeout.map(|timeout| std::time::Instant::now() + timeout);
+        loop {
+            let changes = self.poll()?;
+            if !changes.is_empty() {
+                return Ok(changes);
+            }
+            let mut sleep = WATCH_POLL_INTERVAL;
+            if let Some(deadline) = deadline {
+                let remaining = deadline.saturating_duration_since(std::time::Instant::now());
+                if remaining.is_zero() {
+                    return Ok(changes);
+                }
+                sleep = min(sleep, remaining);
+            }
+            std::thread::sleep(sleep);
+        }
+    }
+}
@@ -6,7 +6,7 @@ use std::fs::OpenOptions;
 use std::hash::Hasher;
 use std::io::SeekFrom::Start;
 use std::io::{Read, Seek, Write};
-use std::ops::Bound::Included;
+use std::ops::Bound::{Excluded, Included, Unbounded};
 use std::ops::{RangeBounds, RangeInclusive};
 use std::path::PathBuf;
 use std::str::FromStr;
@@ -426,13 +426,18 @@ fn reads_update_last_used() -> Result<()> {
     let value = "mundo".as_bytes();
     let (n, _) = handle.single_write_from(key.clone(), value)?;
     assert_eq!(n, 5);
-    let read_ts = handle.read_single(&key)?.unwrap().last_used();
+    // Reads update last_used once their snapshot is taken, so check it without reading.
+    let last_used =
+        || -> Result<Timestamp> { Ok(handle.list_items(&key)?.pop().unwrap().value.last_used()) };
+    handle.read_single(&key)?.unwrap();
+    let read_ts = last_used()?;
     let mut rng = thread_rng();
     let uniform = UniformDuration::new(Duration::from_nanos(0), LAST_USED_RESOLUTION);
     for _ in 0..100 {
         let dither = uniform.sample(&mut rng);
         sleep(LAST_USED_RESOLUTION + dither);
-        let new_read_ts = handle.read_single(&key)?.unwrap().last_used();
+        handle.read_single(&key)?.unwrap();
+        let new_read_ts = last_used()?;
         assert!(new_read_ts > read_ts);
     }
     Ok(())
@@ -536,3 +541,1191 @@ fn test_writeback_mmap() -> anyhow::Result<()> {
     file.write_all(&mmap)?;
     Ok(())
 }
+
+#[test]
+fn conditional_writes() -> Result<()> {
+    let tempdir = tempdir()?;
+    let handle = Handle::new(tempdir.path().to_owned())?;
+    let key = "cas".as_bytes().to_vec();
+    let write_if = |expected, value: &[u8]| -> PubResult<WriteCommitResult> {
+        let mut writer = handle.new_writer()?;
+        let mut value_writer = writer.new_value().begin()?;
+        value_writer.write_all(value)?;
+        writer.stage_write_if(key.clone(), value_writer, expected)?;
+        writer.commit()
+    };
+    let read_value = || -> Result<(Generation, Vec<u8>)> {
+        let value = handle.read_single(&key)?.context("key should exist")?;
+        Ok((value.generation(), value.view(|bytes| bytes.to_vec())?))
+    };
+    write_if(None, "first".as_bytes())?;
+    let (first_generation, bytes) = read_value()?;
+    assert_eq!(bytes, "first".as_bytes());
+    // The key exists now, so put if absent must fail.
+    let mut writer = handle.new_writer()?;
+    let value = writer.new_value().begin()?;
+    writer.put_if_absent(key.clone(), value)?;
+    assert!(matches!(
+        writer.commit(),
+        Err(possum::Error::WriteConflict { expected: None, actual: Some(actual), .. }) if actual == first_generation
+    ));
+    write_if(Some(first_generation), "second".as_bytes())?;
+    let (second_generation, bytes) = read_value()?;
+    assert_ne!(second_generation, first_generation);
+    assert_eq!(bytes, "second".as_bytes());
+    // A stale generation fails, and leaves the committed value alone.
+    assert!(matches!(
+        write_if(Some(first_generation), "third".as_bytes()),
+        Err(possum::Error::WriteConflict { .. })
+    ));
+    assert_eq!(
+        read_value()?,
+        (second_generation, "second".as_bytes().to_vec())
+    );
+    // Recreating a deleted key doesn't repeat a generation.
+    handle.single_delete(&key)?;
+    write_if(None, "fourth".as_bytes())?;
+    let (fourth_generation, _) = read_value()?;
+    assert!(fourth_generation > second_generation);
+    Ok(())
+}
+
+#[test]
+fn get_or_fetch_singleflight() -> Result<()> {
+    let tempdir = tempdir()?;
+    let dir = tempdir.path().to_owned();
+    // Create the manifest before the handles in the threads.
+    Handle::new(dir.clone())?;
+    let key = "fetched".as_bytes();
+    let fetches = std::sync::atomic::AtomicUsize::new(0);
+    std::thread::scope(|scope| -> Result<()> {
+        let mut join_handles = vec![];
+        for _ in 0..4 {
+            join_handles.push(scope.spawn(|| -> Result<Vec<u8>> {
+                let handle = Handle::new(dir.clone())?;
+                let value = handle.get_or_fetch(
+                    key,
+                    |writer| {
+                        fetches.fetch_add(1, std::sync::atomic::Ordering::SeqCst);
+                        sleep(Duration::from_millis(100));
+                        writer.write_all("hello".as_bytes())?;
+                        Ok(())
+                    },
+                    Duration::from_secs(60),
+                )?;
+                Ok(value.view(|bytes| bytes.to_vec())?)
+            }));
+        }
+        for jh in join_handles {
+            assert_eq!(jh.join().unwrap()?, "hello".as_bytes());
+        }
+        Ok(())
+    })?;
+    assert_eq!(fetches.into_inner(), 1);
+    Ok(())
+}
+
+#[test]
+fn get_or_fetch_abandoned_claim() -> Result<()> {
+    let tempdir = tempdir()?;
+    let handle = Handle::new(tempdir.path().to_owned())?;
+    let key = "abandoned".as_bytes();
+    let claim_timeout = Duration::from_millis(100);
+    let started = Instant::now();
+    // A panicking fetch leaves its claim behind, like a crashed process would.
+    let panicked = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| {
+        handle.get_or_fetch(key, |_| panic!("fetch crashed"), claim_timeout)
+    }));
+    assert!(panicked.is_err());
+    let value = handle.get_or_fetch(
+        key,
+        |writer| Ok(writer.write_all("recovered".as_bytes())?),
+        claim_timeout,
+    )?;
+    assert!(started.elapsed() >= claim_timeout);
+    value.view(|bytes| assert_eq!(bytes, "recovered".as_bytes()))?;
+    // A failed fetch releases its claim immediately.
+    let key = "failed".as_bytes();
+    assert!(handle
+        .get_or_fetch(
+            key,
+            |_| Err(anyhow!("fetch failed")),
+            Duration::from_secs(60)
+        )
+        .is_err());
+    let value = handle.get_or_fetch(
+        key,
+        |writer| Ok(writer.write_all("retried".as_bytes())?),
+        Duration::from_secs(60),
+    )?;
+    value.view(|bytes| assert_eq!(bytes, "retried".as_bytes()))?;
+    Ok(())
+}
+
+#[test]
+fn change_feed() -> Result<()> {
+    let tempdir = tempdir()?;
+    let mut handle = Handle::new(tempdir.path().to_owned())?;
+    let start = handle.changes_since(0)?.last().map(|change| change.seq);
+    assert_eq!(start, None);
+    handle.single_write_from("a".as_bytes().to_vec(), "hello".as_bytes())?;
+    handle.single_write_from("a".as_bytes().to_vec(), "world!".as_bytes())?;
+    handle.rename_item("a".as_bytes(), "b".as_bytes())?;
+    handle.single_delete("b".as_bytes())?;
+    handle.single_write_from("c".as_bytes().to_vec(), "evict me".as_bytes())?;
+    handle.set_instance_limits(Limits {
+        max_value_length_sum: Some(4),
+        ..Default::default()
+    })?;
+    handle.single_write_from("d".as_bytes().to_vec(), "ok".as_bytes())?;
+    let changes = handle.changes_since(0)?;
+    let summary: Vec<_> = changes
+        .iter()
+        .map(|change| {
+            (
+                change.op,
+                std::str::from_utf8(&change.key).unwrap(),
+                change.from_key.as_deref(),
+                change.value_length,
+            )
+        })
+        .collect();
+    use ChangeOp::*;
+    assert_eq!(
+        summary,
+        vec![
+            (Write, "a", None, 5),
+            (Write, "a", None, 6),
+            (Rename, "b", Some("a".as_bytes()), 6),
+            (Delete, "b", None, 6),
+            (Write, "c", None, 8),
+            (Write, "d", None, 2),
+            (Evict, "c", None, 8),
+        ]
+    );
+    assert!(changes
+        .iter()
+        .tuple_windows()
+        .all(|(a, b)| b.seq == a.seq + 1));
+    let since = handle.changes_since(changes[4].seq)?;
+    assert_eq!(since, changes[5..]);
+    // Only the most recent changes are retained.
+    handle.set_instance_limits(Limits {
+        max_change_log_len: Some(2),
+        ..Default::default()
+    })?;
+    handle.single_write_from("e".as_bytes().to_vec(), "e".as_bytes())?;
+    let retained = handle.changes_since(0)?;
+    assert_eq!(retained.len(), 2);
+    assert_eq!(retained[0], *changes.last().unwrap());
+    assert_eq!(retained[1].key, "e".as_bytes());
+    Ok(())
+}
+
+#[test]
+fn watch_prefix() -> Result<()> {
+    let tempdir = tempdir()?;
+    let dir = tempdir.path().to_owned();
+    let mut handle = Handle::new(dir.clone())?;
+    handle.single_write_from("watched/before".as_bytes().to_vec(), "".as_bytes())?;
+    let mut watch = handle.watch("watched/".as_bytes())?;
+    // Changes from before the watch started aren't reported.
+    assert!(watch.wait(Some(Duration::from_millis(20)))?.is_empty());
+    std::thread::scope(|scope| -> Result<()> {
+        let writer = scope.spawn(|| -> Result<()> {
+            let handle = Handle::new(dir.clone())?;
+            sleep(Duration::from_millis(50));
+            handle.single_write_from("other".as_bytes().to_vec(), "".as_bytes())?;
+            handle.single_write_from("watched/key".as_bytes().to_vec(), "hi".as_bytes())?;
+            Ok(())
+        });
+        let mut changes = vec![];
+        while changes.is_empty() {
+            changes = watch.wait(Some(Duration::from_secs(10)))?;
+        }
+        writer.join().unwrap()?;
+        assert_eq!(changes.len(), 1);
+        assert_eq!(changes[0].op, ChangeOp::Write);
+        assert_eq!(changes[0].key, "watched/key".as_bytes());
+        Ok(())
+    })?;
+    // Renaming out of the prefix is reported too.
+    handle.move_prefix("watched/".as_bytes(), "moved/".as_bytes())?;
+    let changes = watch.wait(Some(Duration::from_secs(10)))?;
+    assert_eq!(
+        changes.iter().map(|change| change.op).collect_vec(),
+        vec![ChangeOp::Rename, ChangeOp::Rename]
+    );
+    // Falling behind the trimmed change log is reported, and watching carries on from there.
+    handle.set_instance_limits(Limits {
+        max_change_log_len: Some(1),
+        ..Default::default()
+    })?;
+    for key in ["watched/1", "watched/2", "watched/3"] {
+        handle.single_write_from(key.as_bytes().to_vec(), "".as_bytes())?;
+    }
+    assert!(matches!(watch.poll(), Err(possum::Error::ChangesDropped)));
+    handle.single_write_from("watched/4".as_bytes().to_vec(), "".as_bytes())?;
+    let changes = watch.wait(Some(Duration::from_secs(10)))?;
+    assert_eq!(changes.len(), 1);
+    assert_eq!(changes[0].key, "watched/4".as_bytes());
+    Ok(())
+}
+
+#[test]
+fn paginated_listing() -> Result<()> {
+    let tempdir = tempdir()?;
+    let handle = Handle::new(tempdir.path().to_owned())?;
+    let keys = (0..25).map(|i| format!("list/{i:02}")).collect_vec();
+    for key in &keys {
+        handle.single_write_from(key.as_bytes().to_vec(), "".as_bytes())?;
+    }
+    handle.single_write_from("other".as_bytes().to_vec(), "".as_bytes())?;
+    let list = |options: ListOptions, page_size| -> Result<Vec<String>> {
+        handle
+            .iter_items(options)
+            .with_page_size(page_size)
+            .map(|item| Ok(String::from_utf8(item?.key)?))
+            .collect()
+    };
+    let prefix = |prefix: &str| ListOptions {
+        prefix: prefix.as_bytes().to_vec(),
+        ..Default::default()
+    };
+    assert_eq!(list(prefix("list/"), 4)?, keys);
+    assert_eq!(list(prefix("list/"), 5)?, keys);
+    // A page size of 0 is treated as 1 rather than never making progress.
+    assert_eq!(list(prefix("list/"), 0)?, keys);
+    let mut reversed = keys.clone();
+    reversed.reverse();
+    assert_eq!(
+        list(
+            ListOptions {
+                reverse: true,
+                ..prefix("list/")
+            },
+            3
+        )?,
+        reversed
+    );
+    assert_eq!(
+        list(
+            ListOptions {
+                start_after: Some("list/09".as_bytes().to_vec()),
+                limit: Some(7),
+                ..prefix("list/")
+            },
+            4
+        )?,
+        keys[10..17]
+    );
+    let page = handle.list_items_page(&ListOptions {
+        start_after: Some("list/20".as_bytes().to_vec()),
+        limit: Some(10),
+        reverse: true,
+        ..prefix("list/1")
+    })?;
+    assert_eq!(
+        page.into_iter().map(|item| item.key).collect_vec(),
+        keys[10..20]
+            .iter()
+            .rev()
+            .map(|key| key.as_bytes().to_vec())
+            .collect_vec()
+    );
+    Ok(())
+}
+
+#[test]
+fn key_ranges() -> Result<()> {
+    let tempdir = tempdir()?;
+    let handle = Handle::new(tempdir.path().to_owned())?;
+    for ts in 10..20 {
+        handle.single_write_from(format!("logs/{ts}").into_bytes(), "".as_bytes())?;
+    }
+    let keys = |items: Vec<Item>| {
+        items
+            .into_iter()
+            .map(|item| String::from_utf8(item.key).unwrap())
+            .collect_vec()
+    };
+    let start = "logs/12".as_bytes();
+    let end = "logs/15".as_bytes();
+    assert_eq!(
+        keys(handle.list_range((Included(start), Excluded(end)), false, None)?),
+        ["logs/12", "logs/13", "logs/14"]
+    );
+    assert_eq!(
+        keys(handle.list_range((Excluded(start), Included(end)), true, None)?),
+        ["logs/15", "logs/14", "logs/13"]
+    );
+    assert_eq!(
+        keys(handle.list_range((Unbounded, Included(end)), true, Some(2))?),
+        ["logs/15", "logs/14"]
+    );
+    assert_eq!(handle.count_range(..)?, 10);
+    assert_eq!(handle.count_range((Excluded(start), Unbounded))?, 7);
+    let reader = handle.read()?;
+    assert_eq!(reader.count_range((Included(start), Included(end)))?, 4);
+    Ok(())
+}
+
+#[test]
+fn prefix_summaries() -> Result<()> {
+    let tempdir = tempdir()?;
+    let handle = Handle::new(tempdir.path().to_owned())?;
+    for (key, value) in [
+        ("tenants/a/1", "x"),
+        ("tenants/a/2", "xyz"),
+        ("tenants/b/1", "xy"),
+        ("tenants/c", "xyzw"),
+        ("other", "xyzwv"),
+    ] {
+        handle.single_write_from(key.as_bytes().to_vec(), value.as_bytes())?;
+    }
+    let summary = handle.prefix_summary("tenants/".as_bytes())?;
+    assert_eq!(summary.count, 4);
+    assert_eq!(summary.value_length_sum, 10);
+    assert!(summary.oldest_last_used.unwrap() <= summary.newest_last_used.unwrap());
+    assert_eq!(
+        handle.prefix_summary("missing".as_bytes())?,
+        PrefixSummary::default()
+    );
+    let groups = handle.prefix_summary_grouped("tenants/".as_bytes(), "/".as_bytes())?;
+    assert_eq!(
+        groups
+            .iter()
+            .map(|(group, summary)| (
+                std::str::from_utf8(group).unwrap(),
+                summary.count,
+                summary.value_length_sum
+            ))
+            .collect_vec(),
+        [
+            ("tenants/a/", 2, 4),
+            ("tenants/b/", 1, 2),
+            ("tenants/c", 1, 4)
+        ]
+    );
+    assert!(handle
+        .prefix_summary_grouped("tenants/".as_bytes(), &[])
+        .is_err());
+    Ok(())
+}
+
+#[test]
+fn bulk_prefix_ops() -> Result<()> {
+    let tempdir = tempdir()?;
+    let handle = Handle::new(tempdir.path().to_owned())?;
+    for i in 0..10 {
+        handle.single_write_from(format!("a/{i}").into_bytes(), "hello".as_bytes())?;
+    }
+    handle.single_write_from("b".as_bytes().to_vec(), "hello".as_bytes())?;
+    assert_eq!(
+        handle.move_prefix("a/".as_bytes(), b"c/\xff")?,
+        BulkOpStats {
+            count: 10,
+            value_length_sum: 50
+        }
+    );
+    assert_eq!(handle.list_items("a/".as_bytes())?.len(), 0);
+    let value = handle.read_single(b"c/\xff3")?.unwrap();
+    let mut buf = vec![];
+    value.new_reader().read_to_end(&mut buf)?;
+    assert_eq!(buf, "hello".as_bytes());
+    drop(value);
+    // Moving onto existing keys fails without changing anything.
+    handle.single_write_from("d/0".as_bytes().to_vec(), "".as_bytes())?;
+    assert!(handle.move_prefix(b"c/\xff", "d/".as_bytes()).is_err());
+    assert_eq!(handle.count_range(..)?, 12);
+    assert!(handle.delete_prefix_batched("c/".as_bytes(), 0).is_err());
+    assert_eq!(
+        handle.delete_prefix_batched("c/".as_bytes(), 3)?,
+        BulkOpStats {
+            count: 10,
+            value_length_sum: 50
+        }
+    );
+    assert_eq!(handle.delete_prefix("d/".as_bytes())?.count, 1);
+    assert_eq!(
+        handle.delete_prefix("d/".as_bytes())?,
+        BulkOpStats::default()
+    );
+    assert_eq!(
+        handle
+            .list_items(&[])?
+            .into_iter()
+            .map(|item| item.key)
+            .collect_vec(),
+        ["b".as_bytes()]
+    );
+    Ok(())
+}
+
+#[test]
+fn append_to_value() -> Result<()> {
+    let tempdir = tempdir()?;
+    let mut handle = Handle::new(tempdir.path().to_owned())?;
+    let read = |handle: &Handle, key: &str| -> Result<(Vec<u8>, possum::Value)> {
+        let value = handle.read_single(key.as_bytes())?.unwrap();
+        let mut buf = vec![];
+        value.new_reader().read_to_end(&mut buf)?;
+        Ok((buf, *value))
+    };
+    // Make the value a whole block so punching it would be visible.
+    let hello = "hello".repeat(handle.block_size() as usize / 5 + 1);
+    handle.single_write_from("log".as_bytes().to_vec(), hello.as_bytes())?;
+    let (_, before) = read(&handle, "log")?;
+    // The value is at the end of its file, so it's extended in place.
+    let mut writer = handle.new_writer()?;
+    let mut value = writer.append_to("log".as_bytes())?;
+    value.write_all(" world".as_bytes())?;
+    writer.stage_write("log".as_bytes().to_vec(), value)?;
+    writer.commit()?;
+    let (buf, after) = read(&handle, "log")?;
+    assert_eq!(buf, format!("{hello} world").as_bytes());
+    assert_eq!(after.location, {
+        let mut location = before.location;
+        if let ValueLocation::Nonzero(location) = &mut location {
+            location.length = hello.len() as u64 + 6;
+        }
+        location
+    });
+    assert!(after.generation() > before.generation());
+    // Replacing the shorter value didn't punch the extended one.
+    let puncher_done = handle.get_value_puncher_done();
+    drop(handle);
+    puncher_done.wait();
+    handle = Handle::new(tempdir.path().to_owned())?;
+    assert_eq!(read(&handle, "log")?.0, format!("{hello} world").as_bytes());
+    // Another value now follows it, so it's cloned or copied, without updating last_used.
+    handle.single_write_from("other".as_bytes().to_vec(), "x".as_bytes())?;
+    let last_used = |handle: &Handle| -> Result<Timestamp> {
+        Ok(handle.list_items(b"log")?.pop().unwrap().value.last_used())
+    };
+    let before = last_used(&handle)?;
+    std::thread::sleep(Duration::from_millis(2));
+    let mut writer = handle.new_writer()?;
+    let mut value = writer.append_to("log".as_bytes())?;
+    assert_eq!(last_used(&handle)?, before);
+    value.write_all("!".as_bytes())?;
+    writer.stage_write("log".as_bytes().to_vec(), value)?;
+    writer.commit()?;
+    assert_eq!(
+        read(&handle, "log")?.0,
+        format!("{hello} world!").as_bytes()
+    );
+    // Appends conflict with other writes to the key.
+    let mut writer = handle.new_writer()?;
+    let mut value = writer.append_to("log".as_bytes())?;
+    value.write_all("?".as_bytes())?;
+    writer.stage_write("log".as_bytes().to_vec(), value)?;
+    handle.single_write_from("log".as_bytes().to_vec(), "replaced".as_bytes())?;
+    assert!(matches!(
+        writer.commit(),
+        Err(possum::Error::WriteConflict { .. })
+    ));
+    assert!(matches!(
+        handle.new_writer()?.append_to("missing".as_bytes()),
+        Err(NoSuchKey)
+    ));
+    let puncher_done = handle.get_value_puncher_done();
+    drop(handle);
+    puncher_done.wait();
+    handle = Handle::new(tempdir.path().to_owned())?;
+    assert_eq!(read(&handle, "log")?.0, "replaced".as_bytes());
+    Ok(())
+}
+
+#[test]
+fn sparse_values() -> Result<()> {
+    let tempdir = tempdir()?;
+    let handle = Handle::new(tempdir.path().to_owned())?;
+    let key = "object".as_bytes();
+    handle.create_sparse(key.to_vec(), 30)?;
+    let value = handle.read_single(key)?.unwrap();
+    assert_eq!(value.length(), 30);
+    assert_eq!(value.missing_ranges(), std::slice::from_ref(&(0..30)));
+    drop(value);
+    handle.write_range(key, 10, &[1; 10])?;
+    let value = handle.read_single(key)?.unwrap();
+    assert_eq!(value.missing_ranges(), [0..10, 20..30]);
+    assert!(value.is_range_present(10..20));
+    assert!(!value.is_range_present(5..15));
+    let mut buf = vec![];
+    value.new_reader().read_to_end(&mut buf)?;
+    assert_eq!(buf[..10], [0; 10]);
+    assert_eq!(buf[10..20], [1; 10]);
+    drop(value);
+    assert!(handle.write_range(key, 25, &[2; 10]).is_err());
+    handle.write_range(key, 0, &[3; 10])?;
+    handle.write_range(key, 20, &[2; 10])?;
+    // A second handle sees the same state.
+    let other = Handle::new(tempdir.path().to_owned())?;
+    let value = other.read_single(key)?.unwrap();
+    assert!(value.missing_ranges().is_empty());
+    let mut buf = vec![];
+    value.new_reader().read_to_end(&mut buf)?;
+    assert_eq!(buf, [[3; 10], [1; 10], [2; 10]].concat());
+    drop(value);
+    // Replacing the value starts over with everything missing.
+    handle.create_sparse(key.to_vec(), 30)?;
+    assert_eq!(
+        handle.read_single(key)?.unwrap().missing_ranges(),
+        std::slice::from_ref(&(0..30))
+    );
+    assert!(matches!(
+        handle.write_range("missing".as_bytes(), 0, &[0]),
+        Err(NoSuchKey)
+    ));
+    Ok(())
+}
+
+#[test]
+fn preallocated_writes() -> Result<()> {
+    let tempdir = tempdir()?;
+    let handle = Handle::new(tempdir.path().to_owned())?;
+    let read = |key: &str| -> Result<Vec<u8>> {
+        let mut buf = vec![];
+        handle
+            .read_single(key.as_bytes())?
+            .unwrap()
+            .new_reader()
+            .read_to_end(&mut buf)?;
+        Ok(buf)
+    };
+    let mut writer = handle.new_writer()?;
+    // Lengths the platform can't represent fail rather than panic.
+    assert!(writer.new_value().begin_with_size(u64::MAX).is_err());
+    let mut value = writer.new_value().begin_with_size(5)?;
+    value.write_all("hello".as_bytes())?;
+    writer.stage_write("a".as_bytes().to_vec(), value)?;
+    let value = writer.new_value().begin_with_size(0)?;
+    writer.stage_write("empty".as_bytes().to_vec(), value)?;
+    // Writing fewer bytes than reserved fails to stage, and leaves the file usable by the batch.
+    let mut value = writer.new_value().begin_with_size(10)?;
+    value.write_all("short".as_bytes())?;
+    let err = writer
+        .stage_write("b".as_bytes().to_vec(), value)
+        .unwrap_err();
+    assert!(matches!(
+        err.downcast_ref(),
+        Some(possum::Error::ValueLengthMismatch {
+            expected: 10,
+            actual: 5
+        })
+    ));
+    let mut value = writer.new_value().begin()?;
+    value.write_all("c".as_bytes())?;
+    writer.stage_write("c".as_bytes().to_vec(), value)?;
+    writer.commit()?;
+    assert_eq!(read("a")?, "hello".as_bytes());
+    assert!(handle.read_single("b".as_bytes())?.is_none());
+    assert_eq!(read("empty")?, []);
+    assert_eq!(read("c")?, "c".as_bytes());
+    // Abandoned reservations are released.
+    let reserved = 1 << 20;
+    let mut writer = handle.new_writer()?;
+    let mut value = writer.new_value().begin_with_size(reserved)?;
+    value.write_all("abandoned".as_bytes())?;
+    drop(value);
+    drop(writer);
+    let mut allocated = 0;
+    for entry in walk_dir(handle.dir())? {
+        if entry.entry_type == EntryType::ValuesFile {
+            allocated += possum::sys::path_disk_allocation(&entry.path)?;
+        }
+    }
+    assert!(allocated < reserved, "{allocated}");
+    Ok(())
+}
+
+#[test]
+fn positional_value_writes() -> Result<()> {
+    let tempdir = tempdir()?;
+    let handle = Handle::new(tempdir.path().to_owned())?;
+    let read = |key: &str| -> Result<Vec<u8>> {
+        let mut buf = vec![];
+        handle
+            .read_single(key.as_bytes())?
+            .unwrap()
+            .new_reader()
+            .read_to_end(&mut buf)?;
+        Ok(buf)
+    };
+    let mut writer = handle.new_writer()?;
+    // Positional writes need a reserved length, and must fit in it.
+    let mut value = writer.new_value().begin()?;
+    assert!(value.write_at(0, "a".as_bytes()).is_err());
+    drop(value);
+    let mut value = writer.new_value().begin_with_size(10)?;
+    assert!(value.write_at(8, "xyz".as_bytes()).is_err());
+    // Incomplete values aren't staged by default.
+    value.write_at(5, "world".as_bytes())?;
+    let err = writer
+        .stage_write("a".as_bytes().to_vec(), value)
+        .unwrap_err();
+    assert!(matches!(
+        err.downcast_ref(),
+        Some(possum::Error::IncompleteValue { missing }) if missing == std::slice::from_ref(&(0..5))
+    ));
+    // Appends before the first positional write count as written.
+    let mut value = writer.new_value().begin_with_size(10)?;
+    value.write_all("hello".as_bytes())?;
+    value.write_at(5, "world".as_bytes())?;
+    assert!(value.missing_ranges()?.is_empty());
+    writer.stage_write("a".as_bytes().to_vec(), value)?;
+    // Incomplete values can be staged with the rest missing, and filled in later.
+    let mut value = writer.new_value().begin_with_size(10)?;
+    value.write_at(8, "yz".as_bytes())?;
+    value.write_at(0, "ab".as_bytes())?;
+    value.write_at(1, "bc".as_bytes())?;
+    assert_eq!(value.missing_ranges()?, vec![3..8]);
+    writer.stage_incomplete("b".as_bytes().to_vec(), value)?;
+    writer.commit()?;
+    assert_eq!(read("a")?, "helloworld".as_bytes());
+    let b = handle.read_single("b".as_bytes())?.unwrap();
+    assert_eq!(b.missing_ranges(), std::slice::from_ref(&(3..8)));
+    drop(b);
+    handle.write_range("b".as_bytes(), 3, "defgh".as_bytes())?;
+    assert_eq!(read("b")?, "abcdefghyz".as_bytes());
+    assert!(handle
+        .read_single("b".as_bytes())?
+        .unwrap()
+        .missing_ranges()
+        .is_empty());
+    Ok(())
+}
+
+#[test]
+fn mapped_value_writes() -> Result<()> {
+    let tempdir = tempdir()?;
+    let handle = Handle::new(tempdir.path().to_owned())?;
+    let read = |key: &str| -> Result<Vec<u8>> {
+        let mut buf = vec![];
+        handle
+            .read_single(key.as_bytes())?
+            .unwrap()
+            .new_reader()
+            .read_to_end(&mut buf)?;
+        Ok(buf)
+    };
+    let mut writer = handle.new_writer()?;
+    let mut value = writer.new_value().begin()?;
+    value.write_all("head".as_bytes())?;
+    value.map_mut(5)?.copy_from_slice("abcde".as_bytes());
+    // Mappings continue from the end of the value.
+    value.map_mut(3)?.copy_from_slice("xyz".as_bytes());
+    assert!(value.map_mut(0)?.is_empty());
+    assert_eq!(value.value_length()?, 12);
+    writer.stage_write("a".as_bytes().to_vec(), value)?;
+    let mut value = writer.new_value().begin_with_size(4)?;
+    value.map_mut(4)?.copy_from_slice("full".as_bytes());
+    writer.stage_write("b".as_bytes().to_vec(), value)?;
+    writer.commit()?;
+    assert_eq!(read("a")?, "headabcdexyz".as_bytes());
+    assert_eq!(read("b")?, "full".as_bytes());
+    Ok(())
+}
+
+#[cfg(unix)]
+#[test]
+fn zero_copy_value_transfer() -> Result<()> {
+    let tempdir = tempdir()?;
+    let handle = Handle::new(tempdir.path().to_owned())?;
+    let mut src = tempfile::tempfile()?;
+    src.write_all("0123456789".as_bytes())?;
+    let mut writer = handle.new_writer()?;
+    let mut value = writer.new_value().begin()?;
+    let written = value.write_vectored(&[
+        std::io::IoSlice::new("ab".as_bytes()),
+        std::io::IoSlice::new("cd".as_bytes()),
+    ])?;
+    assert_eq!(written, 4);
+    assert_eq!(value.copy_from_file(&src, 2, 5)?, 5);
+    // Copies stop at the end of the source file.
+    assert_eq!(value.copy_from_file(&src, 8, 10)?, 2);
+    value.write_all("!".as_bytes())?;
+    writer.stage_write("a".as_bytes().to_vec(), value)?;
+    writer.commit()?;
+    let expected = "abcd2345689!".as_bytes();
+    let value = handle.read_single("a".as_bytes())?.unwrap();
+    let mut dst = tempfile::tempfile()?;
+    assert_eq!(value.send_to(0, &dst)?, expected.len() as u64);
+    let mut buf = vec![];
+    dst.seek(Start(0))?;
+    dst.read_to_end(&mut buf)?;
+    assert_eq!(buf, expected);
+    let (sender, mut receiver) = std::os::unix::net::UnixStream::pair()?;
+    assert_eq!(value.send_to(0, &sender)?, expected.len() as u64);
+    drop(sender);
+    let mut buf = vec![];
+    receiver.read_to_end(&mut buf)?;
+    assert_eq!(buf, expected);
+    // Pipes are spliced to where that's supported, and sending can start part way.
+    let (pipe_reader, pipe_writer) = nix::unistd::pipe()?;
+    let (mut pipe_reader, pipe_writer) = unsafe {
+        use std::os::fd::FromRawFd;
+        (
+            std::fs::File::from_raw_fd(pipe_reader),
+            std::fs::File::from_raw_fd(pipe_writer),
+        )
+    };
+    assert_eq!(value.send_to(4, &pipe_writer)?, expected.len() as u64 - 4);
+    assert_eq!(value.send_to(expected.len() as u64, &pipe_writer)?, 0);
+    drop(pipe_writer);
+    let mut buf = vec![];
+    pipe_reader.read_to_end(&mut buf)?;
+    assert_eq!(buf, expected[4..]);
+    Ok(())
+}
+
+#[test]
+fn export_values() -> Result<()> {
+    let tempdir = tempdir()?;
+    let handle = Handle::new(tempdir.path().to_owned())?;
+    let export_dir = tempfile::tempdir()?;
+    let export = |key: &str| -> Result<Vec<u8>> {
+        let path = export_dir.path().join(key);
+        handle
+            .read_single(key.as_bytes())?
+            .unwrap()
+            .export_to(&path)?;
+        Ok(std::fs::read(path)?)
+    };
+    // The first value in a file is block-aligned, so whole blocks can be cloned.
+    let block_size = handle.block_size() as usize;
+    let aligned = readable_repeated_bytes(1, 2 * block_size + 3);
+    handle.single_write_from("aligned".as_bytes().to_vec(), aligned.as_slice())?;
+    handle.single_write_from("small".as_bytes().to_vec(), "small".as_bytes())?;
+    assert_eq!(export("aligned")?, aligned);
+    assert_eq!(export("small")?, "small".as_bytes());
+    // Exporting replaces what was there.
+    std::fs::write(export_dir.path().join("small"), aligned.as_slice())?;
+    assert_eq!(export("small")?, "small".as_bytes());
+    handle.single_write_from("empty".as_bytes().to_vec(), [].as_slice())?;
+    assert!(export("empty")?.is_empty());
+    // Missing ranges of sparse values export as zeroes.
+    handle.create_sparse("sparse".as_bytes().to_vec(), 30)?;
+    handle.write_range("sparse".as_bytes(), 10, &[1; 10])?;
+    assert_eq!(export("sparse")?, [[0; 10], [1; 10], [0; 10]].concat());
+    Ok(())
+}
+
+#[test]
+fn clone_files_into_exclusive_file() -> Result<()> {
+    let tempdir = tempdir()?;
+    let handle = Handle::new(tempdir.path().to_owned())?;
+    let block_size = handle.block_size() as usize;
+    let contents = [
+        readable_repeated_bytes(1, block_size + 1),
+        readable_repeated_bytes(2, 3),
+        readable_repeated_bytes(3, 2 * block_size),
+    ];
+    let mut writer = handle.new_writer()?;
+    for (index, bytes) in contents.iter().enumerate() {
+        let mut file = tempfile::tempfile()?;
+        file.write_all(bytes)?;
+        let value = writer.new_value().clone_file(&mut file)?;
+        writer.stage_write(index.to_string().into_bytes(), value)?;
+    }
+    writer.commit()?;
+    for (index, bytes) in contents.iter().enumerate() {
+        let mut buf = vec![];
+        handle
+            .read_single(index.to_string().as_bytes())?
+            .unwrap()
+            .new_reader()
+            .read_to_end(&mut buf)?;
+        assert_eq!(&buf, bytes);
+    }
+    // Clones don't each need their own values file.
+    let values_files = walk_dir(handle.dir())?
+        .into_iter()
+        .filter(|entry| entry.entry_type == EntryType::ValuesFile)
+        .count();
+    assert_eq!(values_files, 1);
+    Ok(())
+}
+
+#[test]
+fn rename_file_into_cache() -> Result<()> {
+    let tempdir = tempdir()?;
+    let handle = Handle::new(tempdir.path().to_owned())?;
+    let downloads = tempfile::tempdir()?;
+    let download = downloads.path().join("download");
+    std::fs::write(&download, "downloaded".as_bytes())?;
+    let mut writer = handle.new_writer()?;
+    let mut value = writer.new_value().rename_file(&download)?;
+    assert!(!download.exists());
+    assert_eq!(value.value_length()?, 10);
+    writer.stage_write("a".as_bytes().to_vec(), value)?;
+    // The renamed file takes new values like any other values file.
+    let mut value = writer.new_value().begin()?;
+    value.write_all("more".as_bytes())?;
+    writer.stage_write("b".as_bytes().to_vec(), value)?;
+    writer.commit()?;
+    let read = |key: &str| -> Result<Vec<u8>> {
+        let mut buf = vec![];
+        handle
+            .read_single(key.as_bytes())?
+            .unwrap()
+            .new_reader()
+            .read_to_end(&mut buf)?;
+        Ok(buf)
+    };
+    assert_eq!(read("a")?, "downloaded".as_bytes());
+    assert_eq!(read("b")?, "more".as_bytes());
+    let mut writer = handle.new_writer()?;
+    assert!(writer.new_value().rename_file(&download).is_err());
+    // Files with other links are left alone, since punching them would change the other links.
+    std::fs::write(&download, "linked".as_bytes())?;
+    let link = downloads.path().join("link");
+    std::fs::hard_link(&download, &link)?;
+    assert!(writer.new_value().rename_file(&download).is_err());
+    assert!(download.exists());
+    assert_eq!(std::fs::read(&link)?, "linked".as_bytes());
+    Ok(())
+}
+
+#[test]
+fn sparse_copies_keep_holes() -> Result<()> {
+    let tempdir = tempdir()?;
+    let handle = Handle::new(tempdir.path().to_owned())?;
+    let block_size = handle.block_size();
+    // Follow an existing value so the copy isn't block-aligned to begin with.
+    handle.single_write_from("before".as_bytes().to_vec(), "before".as_bytes())?;
+    let mut src = tempfile::tempfile()?;
+    src.set_len(4 * block_size)?;
+    positioned_io::WriteAt::write_all_at(&mut src, block_size, &[1; 10])?;
+    positioned_io::WriteAt::write_all_at(&mut src, 3 * block_size, &[2; 5])?;
+    let mut expected = vec![0; 4 * block_size as usize];
+    expected[block_size as usize..][..10].fill(1);
+    expected[3 * block_size as usize..][..5].fill(2);
+    let mut writer = handle.new_writer()?;
+    let value = writer.new_value().clone_file(&mut src)?;
+    writer.stage_write("sparse".as_bytes().to_vec(), value)?;
+    writer.commit()?;
+    let value = handle.read_single("sparse".as_bytes())?.unwrap();
+    let mut buf = vec![];
+    value.new_reader().read_to_end(&mut buf)?;
+    assert_eq!(buf, expected);
+    let mut allocated = 0;
+    for entry in walk_dir(handle.dir())? {
+        if entry.entry_type == EntryType::ValuesFile {
+            allocated += possum::sys::path_disk_allocation(&entry.path)?;
+        }
+    }
+    assert!(allocated < 4 * block_size, "{allocated}");
+    let export_dir = tempfile::tempdir()?;
+    let export_path = export_dir.path().join("sparse");
+    value.export_to(&export_path)?;
+    assert_eq!(std::fs::read(&export_path)?, expected);
+    let allocated = possum::sys::path_disk_allocation(&export_path)?;
+    assert!(allocated < 4 * block_size, "{allocated}");
+    Ok(())
+}
+
+#[test]
+fn import_directory() -> Result<()> {
+    let tempdir = tempdir()?;
+    let handle = Handle::new(tempdir.path().to_owned())?;
+    let src = tempfile::tempdir()?;
+    std::fs::create_dir_all(src.path().join("nested/deeper"))?;
+    std::fs::write(src.path().join("top"), "top")?;
+    std::fs::write(src.path().join("nested/middle"), "middle")?;
+    std::fs::write(src.path().join("nested/deeper/bottom"), "bottom")?;
+    let progress = std::sync::Arc::new(std::sync::Mutex::new(vec![]));
+    let stats = handle.import_dir(
+        src.path(),
+        "files/".as_bytes(),
+        ImportOptions {
+            batch_size: Some(2),
+            progress: Some(Box::new({
+                let progress = progress.clone();
+                move |stats: &BulkOpStats| progress.lock().unwrap().push(stats.count)
+            })),
+            ..Default::default()
+        },
+    )?;
+    assert_eq!(stats.count, 3);
+    assert_eq!(stats.value_length_sum, 15);
+    assert_eq!(*progress.lock().unwrap(), [2, 3]);
+    let read = |key: &str| -> Result<Vec<u8>> {
+        let mut buf = vec![];
+        handle
+            .read_single(key.as_bytes())?
+            .context(key.to_owned())?
+            .new_reader()
+            .read_to_end(&mut buf)?;
+        Ok(buf)
+    };
+    assert_eq!(read("files/top")?, "top".as_bytes());
+    assert_eq!(read("files/nested/middle")?, "middle".as_bytes());
+    assert_eq!(read("files/nested/deeper/bottom")?, "bottom".as_bytes());
+    // Cloned imports leave the sources in place.
+    assert!(src.path().join("top").exists());
+    let stats = handle.import_dir(
+        src.path(),
+        "moved/".as_bytes(),
+        ImportOptions {
+            rename: true,
+            ..Default::default()
+        },
+    )?;
+    assert_eq!(stats.count, 3);
+    assert!(!src.path().join("nested/deeper/bottom").exists());
+    assert_eq!(read("moved/nested/deeper/bottom")?, "bottom".as_bytes());
+    // Files moved before one that fails are kept, even within a batch.
+    std::fs::write(src.path().join("a"), "a")?;
+    std::fs::write(src.path().join("b"), "b")?;
+    std::fs::write(src.path().join("c"), "c")?;
+    // Files with other links can't be moved in.
+    std::fs::hard_link(src.path().join("c"), tempdir.path().join("c link"))?;
+    assert!(handle
+        .import_dir(
+            src.path(),
+            "failed/".as_bytes(),
+            ImportOptions {
+                rename: true,
+                batch_size: Some(10),
+                ..Default::default()
+            },
+        )
+        .is_err());
+    assert_eq!(read("failed/a")?, "a".as_bytes());
+    assert_eq!(read("failed/b")?, "b".as_bytes());
+    assert!(src.path().join("c").exists());
+    // A file whose commit fails is moved back.
+    std::fs::remove_file(tempdir.path().join("c link"))?;
+    let mut conn = rusqlite::Connection::open(tempdir.path().join(MANIFEST_DB_FILE_NAME))?;
+    let tx = conn.transaction_with_behavior(rusqlite::TransactionBehavior::Immediate)?;
+    assert!(handle
+        .import_dir(
+            src.path(),
+            "blocked/".as_bytes(),
+            ImportOptions {
+                rename: true,
+                ..Default::default()
+            },
+        )
+        .is_err());
+    drop(tx);
+    assert_eq!(std::fs::read(src.path().join("c"))?, "c".as_bytes());
+    assert!(handle.read_single("blocked/c".as_bytes())?.is_none());
+    Ok(())
+}
+
+#[test]
+fn export_and_restore_archive() -> Result<()> {
+    let tempdir = tempdir()?;
+    let handle = Handle::new(tempdir.path().to_owned())?;
+    handle.single_write_from("a/one".as_bytes().to_vec(), "one".as_bytes())?;
+    handle.single_write_from("a/empty".as_bytes().to_vec(), "".as_bytes())?;
+    handle.create_sparse("a/sparse".as_bytes().to_vec(), 1 << 20)?;
+    handle.write_range("a/sparse".as_bytes(), 2, "xyz".as_bytes())?;
+    handle.single_write_from("b/other".as_bytes().to_vec(), "other".as_bytes())?;
+    let items = |handle: &Handle| -> Result<Vec<(Vec<u8>, u64, Timestamp)>> {
+        Ok(handle
+            .list_items("a/".as_bytes())?
+            .into_iter()
+            .map(|item| (item.key, item.value.length(), item.value.last_used()))
+            .collect())
+    };
+    let exported_items = items(&handle)?;
+    // Timestamps have millisecond resolution.
+    sleep(Duration::from_millis(2));
+    let mut archive = vec![];
+    let stats = handle.export(&mut archive, "a/".as_bytes())?;
+    assert_eq!(stats.count, 3);
+    assert_eq!(stats.value_length_sum, 3 + (1 << 20));
+    // Only the present bytes of the sparse value are in the archive.
+    assert!(archive.len() < 1000);
+    // Exporting isn't a use of the items.
+    assert_eq!(items(&handle)?, exported_items);
+    let dest_dir = tempfile::tempdir()?;
+    let dest = Handle::new(dest_dir.path().to_owned())?;
+    assert_eq!(dest.restore(archive.as_slice())?, stats);
+    assert_eq!(items(&dest)?, exported_items);
+    for key in ["a/one", "a/empty", "a/sparse"] {
+        let read = |handle: &Handle| -> Result<Vec<u8>> {
+            let mut buf = vec![];
+            handle
+                .read_single(key.as_bytes())?
+                .unwrap()
+                .new_reader()
+                .read_to_end(&mut buf)?;
+            Ok(buf)
+        };
+        assert_eq!(read(&dest)?, read(&handle)?, "{key}");
+    }
+    assert_eq!(
+        dest.read_single("a/sparse".as_bytes())?
+            .unwrap()
+            .missing_ranges(),
+        [0..2, 5..1 << 20]
+    );
+    // Truncated archives are rejected.
+    assert!(matches!(
+        dest.restore(&archive[..archive.len() - 1]),
+        Err(possum::Error::InvalidArchive(_))
+    ));
+    assert!(matches!(
+        dest.restore("not an archive at all".as_bytes()),
+        Err(possum::Error::InvalidArchive(_))
+    ));
+    Ok(())
+}
+
+#[test]
+fn backup_whole_cache() -> Result<()> {
+    let tempdir = tempdir()?;
+    let handle = Handle::new(tempdir.path().to_owned())?;
+    handle.single_write_from("kept".as_bytes().to_vec(), "kept".as_bytes())?;
+    handle.single_write_from("deleted".as_bytes().to_vec(), "deleted".as_bytes())?;
+    handle.create_sparse("sparse".as_bytes().to_vec(), 1 << 20)?;
+    handle.write_range("sparse".as_bytes(), 3, "abc".as_bytes())?;
+    let backup_dir = tempfile::tempdir()?;
+    handle.backup_to(backup_dir.path())?;
+    // Later changes to the source don't show up in the backup.
+    handle.single_delete("deleted".as_bytes())?;
+    handle.single_write_from("kept".as_bytes().to_vec(), "changed".as_bytes())?;
+    let puncher_done = handle.get_value_puncher_done();
+    drop(handle);
+    puncher_done.wait();
+    let backup = Handle::new(backup_dir.path().to_owned())?;
+    let read = |key: &str| -> Result<Vec<u8>> {
+        let mut buf = vec![];
+        backup
+            .read_single(key.as_bytes())?
+            .context(key.to_owned())?
+            .new_reader()
+            .read_to_end(&mut buf)?;
+        Ok(buf)
+    };
+    assert_eq!(read("kept")?, "kept".as_bytes());
+    assert_eq!(read("deleted")?, "deleted".as_bytes());
+    let mut expected = vec![0; 1 << 20];
+    expected[3..6].copy_from_slice("abc".as_bytes());
+    assert_eq!(read("sparse")?, expected);
+    assert_eq!(
+        backup
+            .read_single("sparse".as_bytes())?
+            .unwrap()
+            .missing_ranges(),
+        [0..3, 6..1 << 20]
+    );
+    // Backups won't overwrite anything.
+    assert!(backup.backup_to(backup_dir.path()).is_err());
+    Ok(())
+}
+
+#[test]
+fn named_snapshots() -> Result<()> {
+    let tempdir = tempdir()?;
+    let handle = Handle::new(tempdir.path().to_owned())?;
+    handle.single_write_from("a".as_bytes().to_vec(), "before".as_bytes())?;
+    handle.single_write_from("b".as_bytes().to_vec(), "deleted".as_bytes())?;
+    handle.create_named_snapshot("pinned", None)?;
+    assert!(handle.create_named_snapshot("pinned", None).is_err());
+    assert!(handle.create_named_snapshot("../escape", None).is_err());
+    handle.single_write_from("a".as_bytes().to_vec(), "after".as_bytes())?;
+    handle.single_delete("b".as_bytes())?;
+    handle.single_write_from("c".as_bytes().to_vec(), "new".as_bytes())?;
+    // Another handle, as another process would, sees the view from when the snapshot was taken.
+    let other = Handle::new(tempdir.path().to_owned())?;
+    let snapshot = other.open_named_snapshot("pinned")?;
+    assert_eq!(snapshot.expires(), None);
+    let read = |key: &str| -> Result<Option<Vec<u8>>> {
+        let Some(value) = snapshot.read(key.as_bytes())? else {
+            return Ok(None);
+        };
+        let mut buf = vec![];
+        value.new_reader().read_to_end(&mut buf)?;
+        Ok(Some(buf))
+    };
+    assert_eq!(read("a")?.as_deref(), Some("before".as_bytes()));
+    assert_eq!(read("b")?.as_deref(), Some("deleted".as_bytes()));
+    assert_eq!(read("c")?, None);
+    assert_eq!(snapshot.list_items(&[])?.len(), 2);
+    #[cfg(unix)]
+    {
+        // An open snapshot stays readable after it's released where open files can be removed.
+        assert!(other.release_named_snapshot("pinned")?);
+        assert_eq!(read("a")?.as_deref(), Some("before".as_bytes()));
+        assert_eq!(read("b")?.as_deref(), Some("deleted".as_bytes()));
+        drop(snapshot);
+    }
+    #[cfg(not(unix))]
+    {
+        drop(snapshot);
+        assert!(other.release_named_snapshot("pinned")?);
+    }
+    assert!(!other.release_named_snapshot("pinned")?);
+    assert!(matches!(
+        handle.open_named_snapshot("pinned"),
+        Err(possum::Error::NoSuchSnapshot)
+    ));
+    // Expired snapshots can't be opened, and are removed by cleanup.
+    handle.create_named_snapshot("brief", Some(Duration::ZERO))?;
+    assert!(matches!(
+        handle.open_named_snapshot("brief"),
+        Err(possum::Error::NoSuchSnapshot)
+    ));
+    handle.create_named_snapshot("lasting", Some(Duration::from_secs(3600)))?;
+    handle.cleanup_snapshots()?;
+    assert!(!handle.release_named_snapshot("brief")?);
+    assert!(handle.open_named_snapshot("lasting")?.expires().is_some());
+    // Builds left by a crashed process are removed, and unreadable snapshots don't stop cleanup.
+    let abandoned = tempdir.path().join(".named-snapshot-crashed");
+    std::fs::create_dir_all(abandoned.join("snapshot"))?;
+    std::fs::write(abandoned.join("build.lock"), [])?;
+    std::fs::create_dir(tempdir.path().join("named-snapshot-broken"))?;
+    handle.cleanup_snapshots()?;
+    assert!(!abandoned.exists());
+    assert!(handle.open_named_snapshot("lasting").is_ok());
+    Ok(())
+}
+
+#[test]
+fn reads_dont_block_commits() -> Result<()> {
+    let tempdir = tempdir()?;
+    let handle = Handle::new(tempdir.path().to_owned())?;
+    let writer_handle = Handle::new(tempdir.path().to_owned())?;
+    let block_size = handle.block_size() as usize;
+    let first = readable_repeated_bytes(1, block_size);
+    handle.single_write_from("a".as_bytes().to_vec(), first.as_slice())?;
+    let mut reader = handle.read()?;
+    let value = reader.add("a".as_bytes())?.unwrap();
+    // Another handle replaces the value while the read is in progress, and its puncher tries to
+    // punch the old one.
+    let second = readable_repeated_bytes(2, block_size);
+    writer_handle.single_write_from("a".as_bytes().to_vec(), second.as_slice())?;
+    sleep(Duration::from_millis(50));
+    let snapshot = reader.begin()?;
+    assert_repeated_bytes_values_eq(snapshot.value(value).new_reader(), first.as_slice());
+    assert_repeated_bytes_values_eq(
+        handle.read_single("a".as_bytes())?.unwrap().new_reader(),
+        second.as_slice(),
+    );
+    Ok(())
+}
+
+#[test]
+fn reads_succeed_when_last_used_cant_be_updated() -> Result<()> {
+    let tempdir = tempdir()?;
+    let handle = Handle::new(tempdir.path().to_owned())?;
+    handle.single_write_from("a".as_bytes().to_vec(), "value".as_bytes())?;
+    // Another process holding the manifest write lock makes updating last_used fail.
+    let mut conn = rusqlite::Connection::open(tempdir.path().join(MANIFEST_DB_FILE_NAME))?;
+    let tx = conn.transaction_with_behavior(rusqlite::TransactionBehavior::Immediate)?;
+    let mut buf = vec![];
+    let start = Instant::now();
+    let value = handle.read_single("a".as_bytes())?.unwrap();
+    // The read shouldn't wait out the busy timeout before giving up on last_used.
+    assert!(start.elapsed() < Duration::from_secs(1));
+    value.new_reader().read_to_end(&mut buf)?;
+    assert_eq!(buf, "value".as_bytes());
+    drop(tx);
+    Ok(())
+}